@@ -0,0 +1,785 @@
+use crate::config::{Config, DeviceConfig};
+use crate::device::Device;
+use crate::efficiency;
+use crate::energy;
+use crate::info;
+use crate::limits;
+use crate::pmbus;
+use crate::status;
+use crate::transport::{PmbusTransport, ReadLog};
+use prometheus::proto::MetricFamily;
+use prometheus::{register_counter_vec_with_registry, register_gauge_vec_with_registry, CounterVec, GaugeVec, Registry};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The metrics of the last completed poll, served in place of the gauges so
+/// a scrape never sees a poll half way. Polls are numbered so a scrape can
+/// wait for the one it asked for.
+#[derive(Clone, Default)]
+pub struct Snapshot(Arc<(Mutex<Poll>, Condvar)>);
+
+#[derive(Default)]
+struct Poll {
+    generation: u64,
+    families: Vec<MetricFamily>,
+}
+
+impl Snapshot {
+    pub fn set(&self, families: Vec<MetricFamily>) {
+        let (lock, done) = &*self.0;
+        let mut poll = lock.lock().unwrap();
+        poll.generation += 1;
+        poll.families = families;
+        done.notify_all();
+    }
+
+    /// Number of the last completed poll
+    pub fn generation(&self) -> u64 {
+        self.0 .0.lock().unwrap().generation
+    }
+
+    /// Wait up to `timeout` for a poll after `generation`, false on timeout.
+    pub fn wait_after(&self, generation: u64, timeout: Duration) -> bool {
+        let (lock, done) = &*self.0;
+        let poll = lock.lock().unwrap();
+        let (poll, _) = done.wait_timeout_while(poll, timeout, |poll| poll.generation <= generation).unwrap();
+        poll.generation > generation
+    }
+
+    /// Metrics of the last completed poll
+    pub fn families(&self) -> Vec<MetricFamily> {
+        self.0 .0.lock().unwrap().families.clone()
+    }
+}
+
+/// Every gauge the exporter registers
+pub struct Gauges {
+    /// Configured metrics by name
+    pub metrics: HashMap<String, GaugeVec>,
+    pub command_info: GaugeVec,
+    pub device_info: GaugeVec,
+    pub fan_config: GaugeVec,
+    pub status_flag: GaugeVec,
+    pub limit: GaugeVec,
+    pub rating: GaugeVec,
+    pub utilization: GaugeVec,
+    pub efficiency: GaugeVec,
+    pub expected_efficiency: GaugeVec,
+    pub loss: GaugeVec,
+    pub apparent_power: GaugeVec,
+    pub power_factor: GaugeVec,
+    pub up: GaugeVec,
+    /// Never reset, keeps the time of the last poll the device answered
+    pub last_success: GaugeVec,
+    pub group_delivering: GaugeVec,
+    pub group_output_power: GaugeVec,
+    pub group_capacity: GaugeVec,
+    pub group_redundant: GaugeVec,
+    pub group_imbalance: GaugeVec,
+    pub module_present: GaugeVec,
+    pub read_errors: CounterVec,
+    /// READ_EIN/READ_EOUT totals, never reset
+    pub input_energy: CounterVec,
+    pub output_energy: CounterVec,
+}
+
+impl Gauges {
+    pub fn register(config: &Config, registry: &Registry) -> Self {
+        let prefix = &config.prefix;
+
+        let mut metrics = HashMap::new();
+        for metric in &config.metrics {
+            if metrics.contains_key(&metric.name) {
+                continue;
+            }
+            let gauge = register_gauge_vec_with_registry!(
+                format!("{prefix}_{}", metric.name),
+                metric.help.as_str(),
+                &config.label_names(&metric.name),
+                registry
+            ).unwrap();
+            metrics.insert(metric.name.clone(), gauge);
+        }
+
+        Gauges {
+            metrics,
+            command_info: register_gauge_vec_with_registry!(
+                format!("{prefix}_command_info"),
+                "Commands supported by a device, as found by QUERY or probing",
+                &["bus", "module", "command", "name", "format", "access"],
+                registry
+            ).unwrap(),
+            device_info: register_gauge_vec_with_registry!(
+                format!("{prefix}_device_info"),
+                "Inventory strings of a device (MFR_ID, MFR_MODEL, ...)",
+                &["bus", "module"].into_iter().chain(info::labels()).collect::<Vec<_>>(),
+                registry
+            ).unwrap(),
+            fan_config: register_gauge_vec_with_registry!(
+                format!("{prefix}_fan_config_info"),
+                "Installed fans as reported by FAN_CONFIG_1_2/3_4, unit is what FAN_COMMAND_n is given in",
+                &["bus", "module", "page", "rail", "fan", "unit", "tach_pulses"],
+                registry
+            ).unwrap(),
+            status_flag: register_gauge_vec_with_registry!(
+                format!("{prefix}_status_flag"),
+                "Bits of the STATUS_* registers, 1 when set",
+                &["bus", "module", "page", "rail", "register", "flag"],
+                registry
+            ).unwrap(),
+            limit: register_gauge_vec_with_registry!(
+                format!("{prefix}_limit"),
+                "Fault and warning limits, in the unit of the reading they apply to",
+                &["bus", "module", "page", "rail", "limit"],
+                registry
+            ).unwrap(),
+            rating: register_gauge_vec_with_registry!(
+                format!("{prefix}_rating"),
+                "Ratings of the device (MFR_POUT_MAX, ...), in the unit of the reading they apply to",
+                &["bus", "module", "page", "rail", "rating"],
+                registry
+            ).unwrap(),
+            utilization: register_gauge_vec_with_registry!(
+                format!("{prefix}_utilization_ratio"),
+                "Reading over the rating it is limited by, e.g. READ_POUT / MFR_POUT_MAX",
+                &["bus", "module", "page", "rail", "rating"],
+                registry
+            ).unwrap(),
+            efficiency: register_gauge_vec_with_registry!(
+                format!("{prefix}_efficiency_ratio"),
+                "Output power over input power",
+                &["bus", "module"],
+                registry
+            ).unwrap(),
+            expected_efficiency: register_gauge_vec_with_registry!(
+                format!("{prefix}_expected_efficiency_ratio"),
+                "Efficiency MFR_EFFICIENCY_LL/HL promise at the current output power",
+                &["bus", "module"],
+                registry
+            ).unwrap(),
+            loss: register_gauge_vec_with_registry!(
+                format!("{prefix}_loss_watts"),
+                "Power (W) lost in conversion, input power minus output power",
+                &["bus", "module"],
+                registry
+            ).unwrap(),
+            apparent_power: register_gauge_vec_with_registry!(
+                format!("{prefix}_input_apparent_power_voltamperes"),
+                "Input voltage times input current",
+                &["bus", "module"],
+                registry
+            ).unwrap(),
+            power_factor: register_gauge_vec_with_registry!(
+                format!("{prefix}_power_factor_ratio"),
+                "Estimated power factor, input power over apparent power",
+                &["bus", "module"],
+                registry
+            ).unwrap(),
+            up: register_gauge_vec_with_registry!(
+                format!("{prefix}_up"),
+                "1 when the device answered during the last poll",
+                &["bus", "module", "address"],
+                registry
+            ).unwrap(),
+            last_success: register_gauge_vec_with_registry!(
+                format!("{prefix}_last_success_timestamp_seconds"),
+                "Unix time of the last poll the device answered",
+                &["bus", "module", "address"],
+                registry
+            ).unwrap(),
+            group_delivering: register_gauge_vec_with_registry!(
+                format!("{prefix}_group_modules_delivering"),
+                "Modules of the group with output power",
+                &["group"],
+                registry
+            ).unwrap(),
+            group_output_power: register_gauge_vec_with_registry!(
+                format!("{prefix}_group_output_power_watts"),
+                "Output power (W) of all modules of the group",
+                &["group"],
+                registry
+            ).unwrap(),
+            group_capacity: register_gauge_vec_with_registry!(
+                format!("{prefix}_group_capacity_watts"),
+                "MFR_POUT_MAX (W) of the present modules of the group",
+                &["group"],
+                registry
+            ).unwrap(),
+            group_redundant: register_gauge_vec_with_registry!(
+                format!("{prefix}_group_redundant"),
+                "1 when the load would still be covered without the largest module",
+                &["group"],
+                registry
+            ).unwrap(),
+            group_imbalance: register_gauge_vec_with_registry!(
+                format!("{prefix}_group_load_share_imbalance_ratio"),
+                "Difference between the most and least loaded module over the mean load",
+                &["group"],
+                registry
+            ).unwrap(),
+            module_present: register_gauge_vec_with_registry!(
+                format!("{prefix}_module_present"),
+                "1 when the device answers at its address",
+                &["bus", "module", "address"],
+                registry
+            ).unwrap(),
+            read_errors: register_counter_vec_with_registry!(
+                format!("{prefix}_read_errors_total"),
+                "Reads that failed, by command",
+                &["bus", "module", "command"],
+                registry
+            ).unwrap(),
+            input_energy: register_counter_vec_with_registry!(
+                format!("{prefix}_input_energy_joules_total"),
+                "Energy (J) drawn from outlet, from READ_EIN",
+                &["bus", "module", "page", "rail"],
+                registry
+            ).unwrap(),
+            output_energy: register_counter_vec_with_registry!(
+                format!("{prefix}_output_energy_joules_total"),
+                "Energy (J) provided to the PSU, from READ_EOUT",
+                &["bus", "module", "page", "rail"],
+                registry
+            ).unwrap(),
+        }
+    }
+
+    /// Clear everything read from the devices
+    pub fn reset(&self) {
+        for gauge in self.metrics.values() {
+            gauge.reset();
+        }
+        self.status_flag.reset();
+        self.limit.reset();
+        self.utilization.reset();
+        self.efficiency.reset();
+        self.expected_efficiency.reset();
+        self.loss.reset();
+        self.apparent_power.reset();
+        self.power_factor.reset();
+        self.up.reset();
+        self.module_present.reset();
+        self.group_delivering.reset();
+        self.group_output_power.reset();
+        self.group_capacity.reset();
+        self.group_redundant.reset();
+        self.group_imbalance.reset();
+    }
+
+    /// Everything read at discovery of the present devices, redone whenever
+    /// a module was pulled or inserted
+    pub fn set_discovered(&self, devices: &[Device]) {
+        self.command_info.reset();
+        self.device_info.reset();
+        self.rating.reset();
+        self.fan_config.reset();
+
+        for device in devices.iter().filter(|d| d.present) {
+            self.set_capabilities(device);
+            self.set_info(device);
+            self.set_ratings(device);
+            self.set_fans(device);
+        }
+    }
+
+    /// `up` is only set here for absent devices, `collect_device` sets it
+    /// for the others
+    pub fn set_present(&self, device: &Device, present: bool) {
+        let address = format!("{:#04x}", device.addr);
+        let labels = [device.bus.as_str(), device.module.as_str(), &address];
+
+        self.module_present.with_label_values(&labels).set(present as u8 as f64);
+        if !present {
+            self.up.with_label_values(&labels).set(0.0);
+        }
+    }
+
+    fn set_capabilities(&self, device: &Device) {
+        for (com, capability) in device.capabilities.iter().flatten() {
+            let command = format!("{com:#04x}");
+            let name = pmbus::command(*com).map_or("", |c| c.name);
+            let format = capability.format.map_or("", |f| f.name());
+            self.command_info
+                .with_label_values(&[&device.bus, &device.module, &command, name, format, capability.access()])
+                .set(1.0);
+        }
+    }
+
+    fn set_ratings(&self, device: &Device) {
+        for (page, rating, value) in &device.ratings {
+            let (page_label, rail) = device.page_labels(*page);
+            self.rating
+                .with_label_values(&[&device.bus, &device.module, &page_label, &rail, rating.name])
+                .set(*value);
+        }
+    }
+
+    fn set_fans(&self, device: &Device) {
+        for (page, fan) in &device.fans {
+            let (page_label, rail) = device.page_labels(*page);
+            self.fan_config
+                .with_label_values(&[&device.bus, &device.module, &page_label, &rail, &fan.fan.to_string(), fan.unit(), &fan.tach_pulses.to_string()])
+                .set(1.0);
+        }
+    }
+
+    fn set_info(&self, device: &Device) {
+        if let Some(info) = &device.info {
+            let mut labels = vec![device.bus.as_str(), device.module.as_str()];
+            labels.extend(info.iter().map(String::as_str));
+            self.device_info.with_label_values(&labels).set(1.0);
+        }
+    }
+}
+
+/// Metrics computed from the readings of one poll of `device`, so they
+/// stay consistent even when other polls fail
+fn set_derived(device: &Device, readings: &HashMap<(Option<u8>, u8), f64>, gauges: &Gauges) {
+    let labels = [device.bus.as_str(), device.module.as_str()];
+
+    let vin = readings.get(&(None, pmbus::READ_VIN)).copied();
+    let iin = readings.get(&(None, pmbus::READ_IIN)).copied();
+    let pin = readings.get(&(None, pmbus::READ_PIN)).copied();
+    let pout = device.output_power;
+
+    if let (Some(pin), Some(pout)) = (pin, pout) {
+        gauges.loss.with_label_values(&labels).set(pin - pout);
+        if pin > 0.0 {
+            gauges.efficiency.with_label_values(&labels).set(pout / pin);
+        }
+    }
+    if let Some(expected) = pout.and_then(|pout| efficiency::expected(&device.efficiency, vin, pout)) {
+        gauges.expected_efficiency.with_label_values(&labels).set(expected);
+    }
+
+    if let (Some(vin), Some(iin)) = (vin, iin) {
+        let apparent = vin * iin;
+        gauges.apparent_power.with_label_values(&labels).set(apparent);
+        if let Some(pin) = pin.filter(|_| apparent > 0.0) {
+            gauges.power_factor.with_label_values(&labels).set(pin / apparent);
+        }
+    }
+}
+
+/// Load sharing of every `group` of modules. Redundant means the load would
+/// still be covered (going by MFR_POUT_MAX) if the largest module failed.
+pub fn set_groups(devices: &[Device], gauges: &Gauges) {
+    let mut groups: BTreeMap<&str, Vec<&Device>> = BTreeMap::new();
+    for device in devices {
+        if let Some(group) = &device.group {
+            groups.entry(group).or_default().push(device);
+        }
+    }
+
+    for (group, members) in groups {
+        let present: Vec<&Device> = members.into_iter().filter(|d| d.present).collect();
+
+        let delivering = present.iter().filter(|d| d.output_power.is_some_and(|w| w > 0.0)).count();
+        gauges.group_delivering.with_label_values(&[group]).set(delivering as f64);
+
+        // only known when every present module has a rating
+        let ratings: Option<Vec<f64>> = present.iter().map(|d| d.output_rating()).collect();
+        let ratings = ratings.filter(|r| !r.is_empty());
+        if let Some(ratings) = &ratings {
+            gauges.group_capacity.with_label_values(&[group]).set(ratings.iter().sum());
+        }
+
+        // and the load when every present module reported its output power
+        let outputs: Option<Vec<f64>> = present.iter().map(|d| d.output_power).collect();
+        let outputs = match outputs {
+            Some(outputs) => outputs,
+            None => continue,
+        };
+
+        let load: f64 = outputs.iter().sum();
+        gauges.group_output_power.with_label_values(&[group]).set(load);
+
+        if outputs.len() > 1 && load > 0.0 {
+            let mean = load / outputs.len() as f64;
+            let max = outputs.iter().copied().fold(f64::MIN, f64::max);
+            let min = outputs.iter().copied().fold(f64::MAX, f64::min);
+            gauges.group_imbalance.with_label_values(&[group]).set((max - min) / mean);
+        }
+
+        if let Some(ratings) = ratings {
+            let capacity: f64 = ratings.iter().sum();
+            let largest = ratings.iter().copied().fold(0.0, f64::max);
+            gauges.group_redundant
+                .with_label_values(&[group])
+                .set((capacity - largest >= load) as u8 as f64);
+        }
+    }
+}
+
+/// Read every metric of `device` into `gauges`. Failed reads are counted and
+/// only leave out their own metrics, `up` tells whether the device answered.
+pub fn collect_device<T: PmbusTransport + ?Sized>(config: &Config, device_config: &DeviceConfig, device: &mut Device, bus: &mut T, gauges: &Gauges) {
+    let mut log = ReadLog::default();
+    collect_readings(config, device_config, device, bus, gauges, &mut log);
+
+    let address = format!("{:#04x}", device.addr);
+    let labels = [device.bus.as_str(), device.module.as_str(), &address];
+    gauges.up.with_label_values(&labels).set(log.up() as u8 as f64);
+    if log.answered > 0 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        gauges.last_success.with_label_values(&labels).set(now.as_secs_f64());
+    }
+    for (com, _) in &log.failed {
+        gauges.read_errors
+            .with_label_values(&[&device.bus, &device.module, &format!("{com:#04x}")])
+            .inc();
+    }
+}
+
+fn collect_readings<T: PmbusTransport + ?Sized>(config: &Config, device_config: &DeviceConfig, device: &mut Device, bus: &mut T, gauges: &Gauges, log: &mut ReadLog) {
+    // totals read this time by page (`None` for common metrics) and command
+    let mut readings: HashMap<(Option<u8>, u8), f64> = HashMap::new();
+
+    for metric in config.device_metrics(device_config) {
+        if !device.supports(metric.command) {
+            continue;
+        }
+
+        let pages = device.read_pages();
+        let pages = match metric.common {
+            true => &pages[..1],
+            false => &pages[..],
+        };
+
+        for &page in pages {
+            let phases = match metric.per_phase {
+                true => device.read_phases(),
+                false => vec![None],
+            };
+
+            for phase in phases {
+                let value = match log.record(metric.command, device.read_phase(bus, page, phase, metric.command, metric.format)) {
+                    Some(value) => value,
+                    None => continue,
+                };
+                if phase.is_none() {
+                    readings.insert((page.filter(|_| !metric.common), metric.command), value);
+                }
+
+                let (page_label, rail) = match metric.common {
+                    true => Default::default(),
+                    false => device.page_labels(page),
+                };
+                let phase = phase.map(|p| p.to_string()).unwrap_or_default();
+                let mut labels = vec![device.bus.as_str(), device.module.as_str(), &page_label, &rail, &phase];
+                labels.extend(metric.labels.values().map(String::as_str));
+
+                gauges.metrics[&metric.name]
+                    .with_label_values(&labels)
+                    .set(value);
+            }
+        }
+    }
+
+    for (page, rating, max) in &device.ratings {
+        let value = match rating.reading.and_then(|com| readings.get(&(*page, com))) {
+            Some(value) if *max > 0.0 => value,
+            _ => continue,
+        };
+
+        let (page_label, rail) = device.page_labels(*page);
+        gauges.utilization
+            .with_label_values(&[&device.bus, &device.module, &page_label, &rail, rating.name])
+            .set(value / max);
+    }
+
+    // a page that failed would make the sum look low, it is only known
+    // when READ_POUT was read on every page
+    device.output_power = match readings.get(&(None, pmbus::READ_POUT)) {
+        Some(&watts) => Some(watts),
+        None => device.read_pages().iter()
+            .map(|&page| readings.get(&(page, pmbus::READ_POUT)).copied())
+            .sum(),
+    };
+    set_derived(device, &readings, gauges);
+
+    if device_config.status {
+        for page in device.read_pages() {
+            let flags = status::read_flags(device, bus, page, log);
+
+            let (page_label, rail) = device.page_labels(page);
+            for (register, flag, set) in flags {
+                gauges.status_flag
+                    .with_label_values(&[&device.bus, &device.module, &page_label, &rail, register, flag])
+                    .set(set as u8 as f64);
+            }
+        }
+    }
+
+    if device_config.limits {
+        let refresh = Duration::from_secs(config.limits_refresh);
+        if device.limits_read.is_none_or(|read| read.elapsed() >= refresh) {
+            let mut limits = limits::read_limits(device, bus, log);
+            // the ones that could not be read keep their last value until
+            // the next refresh
+            for old in device.limits.drain(..) {
+                if !limits.iter().any(|(page, name, _)| (*page, *name) == (old.0, old.1)) {
+                    limits.push(old);
+                }
+            }
+            device.limits = limits;
+            device.limits_read = Some(Instant::now());
+        }
+
+        for (page, limit, value) in &device.limits {
+            let (page_label, rail) = device.page_labels(*page);
+            gauges.limit
+                .with_label_values(&[&device.bus, &device.module, &page_label, &rail, limit])
+                .set(*value);
+        }
+    }
+
+    if device_config.energy {
+        for (com, power) in energy::COMMANDS {
+            if !device.supports(com) {
+                continue;
+            }
+
+            // like the input readings READ_EIN is common to every page
+            let (counter, pages) = match com {
+                pmbus::READ_EIN => (&gauges.input_energy, device.read_pages()[..1].to_vec()),
+                _ => (&gauges.output_energy, device.read_pages()),
+            };
+
+            for page in pages {
+                let joules = match log.record(com, energy::read_joules(device, bus, page, com, power)) {
+                    Some(joules) => joules,
+                    None => continue,
+                };
+
+                let (page_label, rail) = match com {
+                    pmbus::READ_EIN => Default::default(),
+                    _ => device.page_labels(page),
+                };
+                counter
+                    .with_label_values(&[&device.bus, &device.module, &page_label, &rail])
+                    .inc_by(joules);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ratings;
+    use crate::transport::MockBus;
+
+    /// First series of `name` labelled with `module`
+    fn value(registry: &Registry, name: &str, module: &str) -> Option<f64> {
+        registry.gather().iter()
+            .filter(|family| family.get_name() == name)
+            .flat_map(|family| family.get_metric())
+            .find(|metric| metric.get_label().iter().any(|l| l.get_name() == "module" && l.get_value() == module))
+            .map(|metric| match metric.has_counter() {
+                true => metric.get_counter().get_value(),
+                false => metric.get_gauge().get_value(),
+            })
+    }
+
+    /// Sum of every `name` series labelled with `module`
+    fn total(registry: &Registry, name: &str, module: &str) -> f64 {
+        registry.gather().iter()
+            .filter(|family| family.get_name() == name)
+            .flat_map(|family| family.get_metric())
+            .filter(|metric| metric.get_label().iter().any(|l| l.get_name() == "module" && l.get_value() == module))
+            .map(|metric| metric.get_counter().get_value())
+            .sum()
+    }
+
+    #[test]
+    fn collects_simulated_chassis() {
+        let config = Config::load(None).unwrap();
+        let registry = Registry::new();
+        let gauges = Gauges::register(&config, &registry);
+        let mut bus = MockBus::fsp_twins();
+
+        for device_config in config.devices.iter().filter(|d| !d.optional) {
+            let mut device = crate::discover(&config, device_config, "test", &mut bus);
+            collect_device(&config, device_config, &mut device, &mut bus, &gauges);
+        }
+
+        assert_eq!(value(&registry, "fsp_twins_exporter_up", "1"), Some(1.0));
+        assert_eq!(value(&registry, "fsp_twins_exporter_up", "2"), Some(1.0));
+        assert_eq!(value(&registry, "fsp_twins_exporter_input_voltage", "1"), Some(115.0));
+        assert_eq!(value(&registry, "fsp_twins_exporter_output_power", "1"), Some(126.0));
+        assert_eq!(value(&registry, "fsp_twins_exporter_output_voltage", "1"), Some(12.0));
+        assert_eq!(value(&registry, "fsp_twins_exporter_efficiency_ratio", "1"), Some(126.0 / 145.0));
+        assert_eq!(value(&registry, "fsp_twins_exporter_read_errors_total", "1"), None);
+    }
+
+    #[test]
+    fn failed_reads_leave_out_their_metrics() {
+        let config = Config::parse(r#"
+            prefix = "test"
+
+            [[metric]]
+            name = "input_voltage"
+            help = "Input voltage"
+            command = "READ_VIN"
+
+            [[metric]]
+            name = "output_current"
+            help = "Output current"
+            command = "READ_IOUT"
+
+            [[device]]
+            module = "1"
+            address = 0x58
+            status = false
+            energy = false
+            limits = false
+
+            [[device]]
+            module = "2"
+            address = 0x59
+            status = false
+            energy = false
+            limits = false
+        "#).unwrap();
+        let registry = Registry::new();
+        let gauges = Gauges::register(&config, &registry);
+        let mut bus = MockBus::new();
+        bus.set_linear11(0x58, pmbus::READ_VIN, 115.0);
+
+        for device_config in &config.devices {
+            let mut device = Device::new(device_config, "test");
+            collect_device(&config, device_config, &mut device, &mut bus, &gauges);
+        }
+
+        assert_eq!(value(&registry, "test_input_voltage", "1"), Some(115.0));
+        assert_eq!(value(&registry, "test_output_current", "1"), None);
+        assert_eq!(value(&registry, "test_read_errors_total", "1"), Some(1.0));
+        assert_eq!(value(&registry, "test_up", "1"), Some(1.0));
+        assert_eq!(value(&registry, "test_up", "2"), Some(0.0));
+    }
+
+    #[test]
+    fn limits_are_only_read_every_refresh() {
+        let config = Config::parse(r#"
+            prefix = "test"
+
+            [[device]]
+            module = "1"
+            address = 0x58
+            status = false
+            energy = false
+        "#).unwrap();
+        let registry = Registry::new();
+        let gauges = Gauges::register(&config, &registry);
+        let mut bus = MockBus::new();
+        bus.set_linear11(0x58, 0x4F, 70.0) // OT_FAULT_LIMIT
+            .set_linear11(0x58, 0x51, 60.0); // OT_WARN_LIMIT, the others fail
+        let mut device = Device::new(&config.devices[0], "test");
+
+        collect_device(&config, &config.devices[0], &mut device, &mut bus, &gauges);
+        let failing = (limits::LIMITS.len() - 2) as f64;
+        assert_eq!(total(&registry, "test_read_errors_total", "1"), failing);
+
+        // not read again before the refresh, even though some failed
+        gauges.reset();
+        collect_device(&config, &config.devices[0], &mut device, &mut bus, &gauges);
+        assert_eq!(total(&registry, "test_read_errors_total", "1"), failing);
+        assert!(value(&registry, "test_limit", "1").is_some());
+
+        // a limit failing on refresh keeps its last value
+        bus.set(0x58, 0x51, &[]);
+        device.limits_read = None;
+        collect_device(&config, &config.devices[0], &mut device, &mut bus, &gauges);
+        assert_eq!(total(&registry, "test_read_errors_total", "1"), 2.0 * failing + 1.0);
+        assert!(device.limits.contains(&(None, "ot_warn", 60.0)));
+        assert!(device.limits.contains(&(None, "ot_fault", 70.0)));
+    }
+
+    #[test]
+    fn output_power_needs_every_page() {
+        let config = Config::parse(r#"
+            prefix = "test"
+
+            [[metric]]
+            name = "input_power"
+            help = "Input power"
+            command = "READ_PIN"
+            common = true
+
+            [[metric]]
+            name = "output_power"
+            help = "Output power"
+            command = "READ_POUT"
+
+            [[device]]
+            module = "1"
+            address = 0x58
+            pages = [0, 1]
+            status = false
+            energy = false
+            limits = false
+        "#).unwrap();
+        let registry = Registry::new();
+        let gauges = Gauges::register(&config, &registry);
+        let mut bus = MockBus::new();
+        bus.set_page(0x58, 1)
+            .set_linear11(0x58, pmbus::READ_PIN, 145.0)
+            .set_page(0x58, 0)
+            .set_linear11(0x58, pmbus::READ_PIN, 145.0)
+            .set_linear11(0x58, pmbus::READ_POUT, 100.0);
+        let mut device = Device::new(&config.devices[0], "test");
+
+        collect_device(&config, &config.devices[0], &mut device, &mut bus, &gauges);
+        assert_eq!(device.output_power, None);
+        assert_eq!(value(&registry, "test_efficiency_ratio", "1"), None);
+        assert_eq!(value(&registry, "test_loss_watts", "1"), None);
+
+        bus.set_page(0x58, 1).set_linear11(0x58, pmbus::READ_POUT, 26.0);
+        collect_device(&config, &config.devices[0], &mut device, &mut bus, &gauges);
+        assert_eq!(device.output_power, Some(126.0));
+        assert_eq!(value(&registry, "test_loss_watts", "1"), Some(19.0));
+    }
+
+    fn group_value(registry: &Registry, name: &str) -> Option<f64> {
+        registry.gather().iter()
+            .find(|family| family.get_name() == name)
+            .map(|family| family.get_metric()[0].get_gauge().get_value())
+    }
+
+    #[test]
+    fn groups_need_the_output_power_of_every_module() {
+        let config = Config::load(None).unwrap();
+        let registry = Registry::new();
+        let gauges = Gauges::register(&config, &registry);
+        let pout_max = ratings::RATINGS.iter().find(|r| r.name == "pout_max").unwrap();
+
+        let mut devices: Vec<Device> = config.devices[..2].iter()
+            .map(|device_config| {
+                let mut device = Device::new(device_config, "test");
+                device.present = true;
+                device.ratings = vec![(None, pout_max, 500.0)];
+                device
+            })
+            .collect();
+        devices[0].output_power = Some(300.0);
+
+        set_groups(&devices, &gauges);
+        assert_eq!(group_value(&registry, "fsp_twins_exporter_group_capacity_watts"), Some(1000.0));
+        assert_eq!(group_value(&registry, "fsp_twins_exporter_group_modules_delivering"), Some(1.0));
+        assert_eq!(group_value(&registry, "fsp_twins_exporter_group_output_power_watts"), None);
+        assert_eq!(group_value(&registry, "fsp_twins_exporter_group_redundant"), None);
+        assert_eq!(group_value(&registry, "fsp_twins_exporter_group_load_share_imbalance_ratio"), None);
+
+        devices[1].output_power = Some(100.0);
+        set_groups(&devices, &gauges);
+        assert_eq!(group_value(&registry, "fsp_twins_exporter_group_output_power_watts"), Some(400.0));
+        assert_eq!(group_value(&registry, "fsp_twins_exporter_group_redundant"), Some(1.0));
+        assert_eq!(group_value(&registry, "fsp_twins_exporter_group_load_share_imbalance_ratio"), Some(1.0));
+
+        devices[0].output_power = Some(600.0);
+        set_groups(&devices, &gauges);
+        assert_eq!(group_value(&registry, "fsp_twins_exporter_group_redundant"), Some(0.0));
+    }
+}
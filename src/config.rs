@@ -0,0 +1,384 @@
+use crate::pmbus::{self, Coefficients, DataFormat, VidTable};
+use serde::{Deserialize, Deserializer};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::fs;
+
+pub const DEFAULT_CONFIG: &str = include_str!("../fsp-twins.toml");
+
+/// Labels every metric has, filled in by the exporter
+pub const RESERVED_LABELS: [&str; 5] = ["bus", "module", "page", "rail", "phase"];
+
+/// Metrics the exporter registers itself under the same prefix
+pub const BUILTIN_METRICS: &[&str] = &[
+    "command_info", "device_info", "fan_config_info", "status_flag", "limit", "rating",
+    "utilization_ratio", "efficiency_ratio", "expected_efficiency_ratio", "loss_watts",
+    "input_apparent_power_voltamperes", "power_factor_ratio", "up", "last_success_timestamp_seconds",
+    "group_modules_delivering", "group_output_power_watts", "group_capacity_watts", "group_redundant",
+    "group_load_share_imbalance_ratio", "module_present", "read_errors_total",
+    "input_energy_joules_total", "output_energy_joules_total",
+];
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "could not read config: {e}"),
+            ConfigError::Parse(e) => write!(f, "could not parse config: {e}"),
+            ConfigError::Invalid(msg) => write!(f, "invalid config: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricConfig {
+    pub name: String,
+    pub help: String,
+    /// Command name from the PMBus spec (`READ_VIN`) or its code
+    #[serde(deserialize_with = "command_code")]
+    pub command: u8,
+    /// Overrides the format the spec assigns to the command
+    pub format: Option<DataFormat>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Read once per device instead of on every page (e.g. input readings)
+    #[serde(default)]
+    pub common: bool,
+    /// Also read every phase of the device on its own
+    #[serde(default)]
+    pub per_phase: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    pub module: String,
+    pub address: u16,
+    /// Falls back to the device given on the command line
+    pub bus: Option<String>,
+    /// Names of the metrics to read, all of them when not given
+    pub metrics: Option<Vec<String>>,
+    /// `direct` when the device reports in DIRECT instead of LINEAR11
+    pub format: Option<DataFormat>,
+    /// DIRECT coefficients per command, read from COEFFICIENTS otherwise
+    #[serde(default, deserialize_with = "command_coefficients")]
+    pub coefficients: HashMap<u8, Coefficients>,
+    /// VID code table, taken from VOUT_MODE when not given
+    pub vid: Option<VidTable>,
+    /// Pages to read, found by probing PAGE when not given unless QUERY
+    /// reports it as not writable
+    pub pages: Option<Vec<u8>>,
+    /// `rail` label per page
+    #[serde(default, deserialize_with = "page_rails")]
+    pub rails: HashMap<u8, String>,
+    /// Number of phases selectable through PHASE on every page
+    #[serde(default)]
+    pub phases: u8,
+    /// Export the STATUS_* registers as flags
+    #[serde(default = "enabled")]
+    pub status: bool,
+    /// Export READ_EIN/READ_EOUT as energy counters
+    #[serde(default = "enabled")]
+    pub energy: bool,
+    /// Export the fault and warning limits
+    #[serde(default = "enabled")]
+    pub limits: bool,
+    /// Modules sharing the load of one output, for the redundancy metrics
+    pub group: Option<String>,
+    /// Leave the device out when nothing answers at startup
+    #[serde(default)]
+    pub optional: bool,
+    /// Never write to the device (PAGE, PHASE, QUERY, COEFFICIENTS, clearing
+    /// faults), only the configured metrics are read
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+fn limits_refresh() -> u64 {
+    300
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub prefix: String,
+    /// Seconds between reads of the limit registers
+    #[serde(default = "limits_refresh")]
+    pub limits_refresh: u64,
+    #[serde(default, rename = "metric")]
+    pub metrics: Vec<MetricConfig>,
+    #[serde(default, rename = "device")]
+    pub devices: Vec<DeviceConfig>,
+}
+
+fn command_code<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum CommandRef {
+        Code(u8),
+        Name(String),
+    }
+
+    match CommandRef::deserialize(deserializer)? {
+        CommandRef::Code(code) => Ok(code),
+        CommandRef::Name(name) => pmbus::lookup(&name)
+            .map(|c| c.code)
+            .ok_or_else(|| serde::de::Error::custom(format!("unknown pmbus command {name}"))),
+    }
+}
+
+fn command_coefficients<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<u8, Coefficients>, D::Error> {
+    BTreeMap::<String, Coefficients>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(name, c)| match pmbus::lookup(&name) {
+            Some(command) => Ok((command.code, c)),
+            None => Err(serde::de::Error::custom(format!("unknown pmbus command {name}"))),
+        })
+        .collect()
+}
+
+fn page_rails<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<u8, String>, D::Error> {
+    BTreeMap::<String, String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(page, rail)| match page.parse::<u8>() {
+            Ok(page) => Ok((page, rail)),
+            Err(_) => Err(serde::de::Error::custom(format!("invalid page {page}"))),
+        })
+        .collect()
+}
+
+impl Config {
+    pub fn load(path: Option<&str>) -> Result<Self, ConfigError> {
+        let text = match path {
+            Some(path) => fs::read_to_string(path).map_err(ConfigError::Io)?,
+            None => DEFAULT_CONFIG.to_string(),
+        };
+
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        let config: Config = toml::from_str(text).map_err(ConfigError::Parse)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let mut label_keys: HashMap<&str, BTreeSet<&str>> = HashMap::new();
+        let mut helps: HashMap<&str, &str> = HashMap::new();
+
+        if !valid_name(&self.prefix, true) {
+            return Err(ConfigError::Invalid(format!(
+                "prefix `{}` is not a valid metric name", self.prefix
+            )));
+        }
+
+        for metric in &self.metrics {
+            if !valid_name(&metric.name, true) {
+                return Err(ConfigError::Invalid(format!(
+                    "metric {}: not a valid metric name", metric.name
+                )));
+            }
+            if let Some(key) = metric.labels.keys().find(|k| !valid_name(k, false) || k.starts_with("__")) {
+                return Err(ConfigError::Invalid(format!(
+                    "metric {}: `{key}` is not a valid label name", metric.name
+                )));
+            }
+            let format = metric.format.or_else(|| pmbus::command(metric.command).map(|c| c.format));
+            if format == Some(DataFormat::NonNumeric) {
+                return Err(ConfigError::Invalid(format!(
+                    "metric {}: command {:#04x} is not numeric, give it a format", metric.name, metric.command
+                )));
+            }
+            if BUILTIN_METRICS.contains(&metric.name.as_str()) {
+                return Err(ConfigError::Invalid(format!(
+                    "metric {}: name is taken by a metric of the exporter", metric.name
+                )));
+            }
+            if *helps.entry(&metric.name).or_insert(&metric.help) != metric.help {
+                return Err(ConfigError::Invalid(format!(
+                    "metric {}: every entry must have the same help", metric.name
+                )));
+            }
+
+            let keys: BTreeSet<&str> = metric.labels.keys().map(String::as_str).collect();
+            if let Some(key) = RESERVED_LABELS.iter().find(|k| keys.contains(*k)) {
+                return Err(ConfigError::Invalid(format!(
+                    "metric {}: label `{key}` is reserved", metric.name
+                )));
+            }
+
+            match label_keys.get(metric.name.as_str()) {
+                Some(known) if *known != keys => {
+                    return Err(ConfigError::Invalid(format!(
+                        "metric {}: every entry must declare the same labels", metric.name
+                    )));
+                }
+                Some(_) => {}
+                None => {
+                    label_keys.insert(&metric.name, keys);
+                }
+            }
+        }
+
+        for device in &self.devices {
+            if !matches!(device.format, None | Some(DataFormat::Linear11) | Some(DataFormat::Direct)) {
+                return Err(ConfigError::Invalid(format!(
+                    "device {}: format must be linear11 or direct", device.module
+                )));
+            }
+            if device.phases == pmbus::ALL_PHASES {
+                return Err(ConfigError::Invalid(format!(
+                    "device {}: at most 254 phases are addressable", device.module
+                )));
+            }
+            if device.read_only && (device.pages.is_some() || device.phases > 0) {
+                return Err(ConfigError::Invalid(format!(
+                    "device {}: pages and phases are selected by writing, not possible with read_only", device.module
+                )));
+            }
+            if device.coefficients.values().any(|c| c.m == 0) {
+                return Err(ConfigError::Invalid(format!(
+                    "device {}: coefficient m can not be 0", device.module
+                )));
+            }
+
+            for name in device.metrics.iter().flatten() {
+                if !label_keys.contains_key(name.as_str()) {
+                    return Err(ConfigError::Invalid(format!(
+                        "device {}: unknown metric {name}", device.module
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Label names of `name`'s gauge, the reserved ones first
+    pub fn label_names(&self, name: &str) -> Vec<&str> {
+        let mut names = RESERVED_LABELS.to_vec();
+        if let Some(metric) = self.metrics.iter().find(|m| m.name == name) {
+            names.extend(metric.labels.keys().map(String::as_str));
+        }
+
+        names
+    }
+
+    /// Metrics to read from `device`, in declaration order
+    pub fn device_metrics<'a>(&'a self, device: &'a DeviceConfig) -> impl Iterator<Item = &'a MetricConfig> {
+        self.metrics.iter().filter(move |m| match &device.metrics {
+            Some(names) => names.contains(&m.name),
+            None => true,
+        })
+    }
+}
+
+/// Prometheus metric names are `[a-zA-Z_:][a-zA-Z0-9_:]*`, label names the
+/// same without colons
+fn valid_name(name: &str, colons: bool) -> bool {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || c == '_' || (colons && c == ':');
+
+    match name.chars().next() {
+        Some(first) => !first.is_ascii_digit() && name.chars().all(allowed),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METRIC: &str = r#"
+        prefix = "test"
+
+        [[metric]]
+        name = "fan_rpm"
+        help = "Speed of the fan"
+        command = "READ_FAN_SPEED_1"
+        labels = { fan = "1" }
+    "#;
+
+    fn invalid(text: &str) -> String {
+        match Config::parse(text) {
+            Err(ConfigError::Invalid(msg)) => msg,
+            other => panic!("expected an invalid config, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        Config::load(None).unwrap();
+    }
+
+    #[test]
+    fn rejects_builtin_names() {
+        let msg = invalid(&METRIC.replace(r#"name = "fan_rpm""#, r#"name = "up""#));
+        assert!(msg.contains("metric up"), "{msg}");
+    }
+
+    #[test]
+    fn rejects_entries_with_other_help() {
+        let text = format!("{METRIC}{}", METRIC.replace(r#"prefix = "test""#, "").replace("Speed of", "Rpm of").replace(r#""1""#, r#""2""#));
+        let msg = invalid(&text);
+        assert!(msg.contains("same help"), "{msg}");
+    }
+
+    #[test]
+    fn rejects_entries_with_other_labels() {
+        let text = format!("{METRIC}{}", METRIC.replace(r#"prefix = "test""#, "").replace("fan = ", "sensor = "));
+        let msg = invalid(&text);
+        assert!(msg.contains("same labels"), "{msg}");
+    }
+
+    #[test]
+    fn rejects_reserved_labels() {
+        let msg = invalid(&METRIC.replace("fan = ", "page = "));
+        assert!(msg.contains("reserved"), "{msg}");
+    }
+
+    #[test]
+    fn rejects_pages_of_read_only_devices() {
+        let text = format!("{METRIC}\n[[device]]\nmodule = \"atx\"\naddress = 0x25\nread_only = true\npages = [0, 1]");
+        let msg = invalid(&text);
+        assert!(msg.contains("read_only"), "{msg}");
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        let msg = invalid(&METRIC.replace(r#"name = "fan_rpm""#, r#"name = "fan-rpm""#));
+        assert!(msg.contains("not a valid metric name"), "{msg}");
+
+        let msg = invalid(&METRIC.replace(r#"prefix = "test""#, r#"prefix = "1psu""#));
+        assert!(msg.contains("prefix"), "{msg}");
+
+        for key in ["fan-id", "__fan", "fan:id"] {
+            let msg = invalid(&METRIC.replace("fan = ", &format!("\"{key}\" = ")));
+            assert!(msg.contains("not a valid label name"), "{msg}");
+        }
+    }
+
+    #[test]
+    fn rejects_non_numeric_commands_without_format() {
+        let text = METRIC.replace("READ_FAN_SPEED_1", "FAN_CONFIG_1_2");
+        let msg = invalid(&text);
+        assert!(msg.contains("not numeric"), "{msg}");
+
+        let text = text.replace("labels = ", "format = \"raw\"\nlabels = ");
+        Config::parse(&text).unwrap();
+    }
+}
@@ -0,0 +1,774 @@
+use crate::config::{DeviceConfig, MetricConfig};
+use crate::efficiency::EfficiencyCurve;
+use crate::energy::{self, EnergySample};
+use crate::fans::FanConfig;
+use crate::pmbus::{self, Capability, Coefficients, DataFormat, QueryFormat, Size, VidTable};
+use crate::ratings::Rating;
+use crate::transport::{PMBusError, PMBusResult, PmbusTransport};
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// Runtime state of a configured device: where it lives and whatever is
+/// needed to decode its readings.
+pub struct Device {
+    pub bus: String,
+    pub module: String,
+    pub addr: u16,
+    /// Format of the commands the spec lists as LINEAR11
+    linear_format: DataFormat,
+    coefficients: HashMap<u8, Coefficients>,
+    /// Overrides the VID table selected by VOUT_MODE
+    vid_table: Option<VidTable>,
+    /// Empty for devices without (or with a single) PAGE
+    pub pages: Vec<u8>,
+    rails: HashMap<u8, String>,
+    page_plus_read: bool,
+    /// Last page written to PAGE, `None` when unknown
+    current_page: Option<u8>,
+    /// Phases per page, 0 for single phase devices
+    pub phases: u8,
+    /// PHASE points at a single phase, PAGE_PLUS_READ can not be used
+    phase_selected: bool,
+    /// Supported commands, `None` until the device answered discovery
+    pub capabilities: Option<HashMap<u8, Capability>>,
+    /// Previous READ_EIN/READ_EOUT reading per page and command
+    pub energy: HashMap<(Option<u8>, u8), EnergySample>,
+    /// Last read `(page, limit, value)` of the limit registers
+    pub limits: Vec<(Option<u8>, &'static str, f64)>,
+    pub limits_read: Option<Instant>,
+    /// MFR_ID, MFR_MODEL, ... in `info::INFO` order, read once
+    pub info: Option<Vec<String>>,
+    /// `(page, rating, value)` of the MFR_* ratings, read once
+    pub ratings: Vec<(Option<u8>, &'static Rating, f64)>,
+    /// MFR_EFFICIENCY_LL/HL, read once
+    pub efficiency: Vec<EfficiencyCurve>,
+    /// `(page, fan)` of the installed fans, read once
+    pub fans: Vec<(Option<u8>, FanConfig)>,
+    /// Only ever read from, see `DeviceConfig::read_only`
+    read_only: bool,
+    /// Read to tell whether the device is there
+    probe_command: u8,
+    /// Answered the last probe
+    pub present: bool,
+    /// Load sharing group of the device
+    pub group: Option<String>,
+    /// READ_POUT of the last poll summed over the pages, `None` unless it
+    /// was read on all of them
+    pub output_power: Option<f64>,
+}
+
+impl Device {
+    pub fn new(config: &DeviceConfig, bus: &str) -> Self {
+        Device {
+            bus: bus.to_string(),
+            module: config.module.clone(),
+            addr: config.address,
+            linear_format: config.format.unwrap_or(DataFormat::Linear11),
+            coefficients: config.coefficients.clone(),
+            vid_table: config.vid,
+            pages: config.pages.clone().unwrap_or_default(),
+            rails: config.rails.clone(),
+            page_plus_read: false,
+            current_page: None,
+            phases: config.phases,
+            phase_selected: false,
+            capabilities: None,
+            energy: HashMap::new(),
+            limits: Vec::new(),
+            limits_read: None,
+            info: None,
+            ratings: Vec::new(),
+            efficiency: Vec::new(),
+            fans: Vec::new(),
+            read_only: config.read_only,
+            probe_command: pmbus::STATUS_BYTE,
+            present: false,
+            group: config.group.clone(),
+            output_power: None,
+        }
+    }
+
+    /// Find the pages of the device (unless configured), whether it supports
+    /// PAGE_PLUS_READ, which commands (of `metrics` and `extra`) it supports
+    /// and fetch COEFFICIENTS for every DIRECT command that has none configured.
+    /// Probing sets STATUS_CML bits, see `clear_cml`. Read-only devices are
+    /// only asked for the commands of `metrics`, by reading them.
+    pub fn discover<'a, T: PmbusTransport + ?Sized>(&mut self, bus: &mut T, metrics: impl Iterator<Item = &'a MetricConfig>, extra: &[u8]) {
+        let metrics: Vec<&MetricConfig> = metrics.collect();
+
+        if self.read_only {
+            let commands: Vec<u8> = metrics.iter().map(|m| m.command).collect();
+            self.capabilities = self.read_capabilities(bus, None, &commands);
+            // nothing says it has STATUS_BYTE, probe with a command it answered
+            let readable: Vec<u8> = commands.iter().copied()
+                .filter(|&com| pmbus::command(com).is_none_or(|c| c.size != Size::Send))
+                .collect();
+            if let Some(&com) = readable.iter().find(|&&com| self.supports(com)).or(readable.first()) {
+                self.probe_command = com;
+            }
+            return;
+        }
+
+        // PAGE writes a device does not take are CML faults, only probe it
+        // when QUERY lists it as writable
+        // devices without QUERY are probed too, only those telling PAGE is not
+        // supported or not writable are not
+        let probe_pages = self.pages.is_empty();
+        let page_writable = match pmbus::query(bus, self.addr, pmbus::PAGE) {
+            Ok(Some(capability)) => capability.write,
+            Ok(None) => false,
+            Err(_) => true,
+        };
+        if probe_pages && page_writable {
+            self.pages = self.find_pages(bus);
+        }
+        if probe_pages && self.pages.len() == 1 {
+            self.pages.clear();
+        }
+        if let Some(&page) = self.pages.first() {
+            self.page_plus_read = bus.process_block(self.addr, pmbus::PAGE_PLUS_READ, &[page, pmbus::STATUS_BYTE])
+                .is_ok_and(|data| !data.is_empty());
+        }
+
+        let page = self.pages.first().copied();
+        let mut commands: Vec<u8> = metrics.iter().map(|m| m.command).collect();
+        commands.extend(extra);
+        self.capabilities = self.find_capabilities(bus, page, &commands);
+
+        let vout_direct = matches!(self.read_byte(bus, page, pmbus::VOUT_MODE), Ok(mode) if mode >> 5 == 0b010);
+
+        let mut direct: Vec<u8> = metrics.iter()
+            .map(|m| (m.command, m.format))
+            .chain(extra.iter().map(|&com| (com, None)))
+            .filter(|&(com, format)| match self.format(com, format) {
+                DataFormat::Direct => self.supports(com),
+                DataFormat::ULinear16 => vout_direct && self.supports(com),
+                _ => false,
+            })
+            .map(|(com, _)| com)
+            .collect();
+        // energy accumulators count in the format of their power reading
+        for (energy, power) in energy::COMMANDS {
+            if extra.contains(&energy) && self.supports(energy) && self.is_direct(power) {
+                direct.push(energy);
+            }
+        }
+
+        for com in direct {
+            if self.coefficients.contains_key(&com) {
+                continue;
+            }
+
+            match pmbus::read_coefficients(bus, self.addr, com) {
+                Ok(c) => {
+                    self.coefficients.insert(com, c);
+                }
+                Err(e) => eprintln!("{}@{:#04x}: no coefficients for {com:#04x}: {e}", self.bus, self.addr),
+            }
+        }
+    }
+
+    /// STATUS_CML as it is now, `None` when it can not be read
+    pub fn read_cml<T: PmbusTransport + ?Sized>(&self, bus: &mut T) -> Option<u8> {
+        bus.read_byte(self.addr, pmbus::STATUS_CML).ok()
+    }
+
+    /// Clear the STATUS_CML bits of every page that were not set `before`
+    /// (writing 1 clears a bit). Probing unsupported commands and pages sets
+    /// invalid command/data, which would show up as faults until the next
+    /// CLEAR_FAULTS otherwise.
+    pub fn clear_cml<T: PmbusTransport + ?Sized>(&mut self, bus: &mut T, before: Option<u8>) {
+        let before = match before {
+            Some(before) if !self.read_only => before,
+            _ => return,
+        };
+
+        for page in self.read_pages() {
+            let caused = match self.read_byte(bus, page, pmbus::STATUS_CML) {
+                Ok(now) => now & !before,
+                Err(_) => continue,
+            };
+            if caused != 0 {
+                let cleared = self.select_page(bus, page)
+                    .and_then(|_| bus.write_byte(self.addr, pmbus::STATUS_CML, caused));
+                if let Err(e) = cleared {
+                    eprintln!("{}@{:#04x}: could not clear STATUS_CML: {e}", self.bus, self.addr);
+                }
+            }
+        }
+    }
+
+    /// Whether anything answers at the address. Every PMBus device has
+    /// STATUS_BYTE, read-only devices are probed with one of their metrics.
+    pub fn probe<T: PmbusTransport + ?Sized>(&self, bus: &mut T) -> bool {
+        match pmbus::command(self.probe_command).map_or(Size::Word, |c| c.size) {
+            Size::Byte => bus.read_byte(self.addr, self.probe_command).is_ok(),
+            Size::Block => bus.read_block(self.addr, self.probe_command).is_ok(),
+            _ => bus.read_word(self.addr, self.probe_command).is_ok(),
+        }
+    }
+
+    /// Every command of the spec (and `commands`) the device claims through
+    /// QUERY, or which of `commands` can be read when QUERY is not supported.
+    fn find_capabilities<T: PmbusTransport + ?Sized>(&mut self, bus: &mut T, page: Option<u8>, commands: &[u8]) -> Option<HashMap<u8, Capability>> {
+        let mut capabilities = HashMap::new();
+
+        if self.select_page(bus, page).is_ok() && matches!(pmbus::query(bus, self.addr, pmbus::QUERY), Ok(Some(_))) {
+            let known = pmbus::COMMANDS.iter().map(|c| c.code);
+            for com in known.chain(commands.iter().copied()) {
+                if let Ok(Some(capability)) = pmbus::query(bus, self.addr, com) {
+                    capabilities.insert(com, capability);
+                }
+            }
+
+            return Some(capabilities);
+        }
+
+        self.read_capabilities(bus, page, commands)
+    }
+
+    /// Which of `commands` can be read
+    fn read_capabilities<T: PmbusTransport + ?Sized>(&mut self, bus: &mut T, page: Option<u8>, commands: &[u8]) -> Option<HashMap<u8, Capability>> {
+        let mut capabilities = HashMap::new();
+
+        for &com in commands {
+            if capabilities.contains_key(&com) {
+                continue;
+            }
+
+            let size = pmbus::command(com).map_or(Size::Word, |c| c.size);
+            let readable = match size {
+                Size::Byte => self.read_byte(bus, page, com).is_ok(),
+                Size::Word => self.read_word(bus, page, com).is_ok(),
+                Size::Block => self.read_block(bus, page, com).is_ok(),
+                Size::Send => false,
+            };
+            if readable {
+                capabilities.insert(com, Capability { read: true, write: false, format: None });
+            }
+        }
+
+        match capabilities.is_empty() {
+            true => None,
+            false => Some(capabilities),
+        }
+    }
+
+    /// Whether `com` can be read, assumed when nothing is known yet
+    pub fn supports(&self, com: u8) -> bool {
+        match &self.capabilities {
+            Some(capabilities) => capabilities.get(&com).is_some_and(|c| c.read),
+            None => true,
+        }
+    }
+
+    /// Pages that accept being written to PAGE and read back the same
+    fn find_pages<T: PmbusTransport + ?Sized>(&mut self, bus: &mut T) -> Vec<u8> {
+        let mut pages = Vec::new();
+        for page in 0..0x20 {
+            let selected = bus.write_byte(self.addr, pmbus::PAGE, page)
+                .and_then(|_| bus.read_byte(self.addr, pmbus::PAGE));
+            match selected {
+                Ok(p) if p == page => pages.push(page),
+                _ => break,
+            }
+        }
+        self.current_page = None;
+
+        pages
+    }
+
+    /// Pages to read a metric on, `None` for unpaged devices
+    pub fn read_pages(&self) -> Vec<Option<u8>> {
+        match self.pages.is_empty() {
+            true => vec![None],
+            false => self.pages.iter().map(|&p| Some(p)).collect(),
+        }
+    }
+
+    /// Phases to read a per phase metric on, `None` being the total
+    pub fn read_phases(&self) -> Vec<Option<u8>> {
+        let mut phases = vec![None];
+        phases.extend((0..self.phases).map(Some));
+
+        phases
+    }
+
+    /// `page` and `rail` label values of `page`
+    pub fn page_labels(&self, page: Option<u8>) -> (String, String) {
+        match page {
+            Some(page) => (page.to_string(), self.rails.get(&page).cloned().unwrap_or_default()),
+            None => (String::new(), String::new()),
+        }
+    }
+
+    fn select_page<T: PmbusTransport + ?Sized>(&mut self, bus: &mut T, page: Option<u8>) -> PMBusResult<()> {
+        match page {
+            Some(page) if self.current_page != Some(page) => {
+                self.current_page = None;
+                bus.write_byte(self.addr, pmbus::PAGE, page)?;
+                self.current_page = Some(page);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn page_plus_read<T: PmbusTransport + ?Sized>(&self, bus: &mut T, page: u8, com: u8, len: usize) -> PMBusResult<Vec<u8>> {
+        let data = bus.process_block(self.addr, pmbus::PAGE_PLUS_READ, &[page, com])?;
+        match data.len() >= len {
+            true => Ok(data),
+            false => Err(PMBusError::InvalidData { com, what: "short PAGE_PLUS_READ response" }),
+        }
+    }
+
+    pub fn read_byte<T: PmbusTransport + ?Sized>(&mut self, bus: &mut T, page: Option<u8>, com: u8) -> PMBusResult<u8> {
+        match page {
+            Some(page) if self.page_plus_read && !self.phase_selected => Ok(self.page_plus_read(bus, page, com, 1)?[0]),
+            _ => {
+                self.select_page(bus, page)?;
+                bus.read_byte(self.addr, com)
+            }
+        }
+    }
+
+    pub fn read_word<T: PmbusTransport + ?Sized>(&mut self, bus: &mut T, page: Option<u8>, com: u8) -> PMBusResult<u16> {
+        match page {
+            Some(page) if self.page_plus_read && !self.phase_selected => {
+                let data = self.page_plus_read(bus, page, com, 2)?;
+                Ok(u16::from_le_bytes([data[0], data[1]]))
+            }
+            _ => {
+                self.select_page(bus, page)?;
+                bus.read_word(self.addr, com)
+            }
+        }
+    }
+
+    pub fn read_block<T: PmbusTransport + ?Sized>(&mut self, bus: &mut T, page: Option<u8>, com: u8) -> PMBusResult<Vec<u8>> {
+        match page {
+            Some(page) if self.page_plus_read && !self.phase_selected => self.page_plus_read(bus, page, com, 0),
+            _ => {
+                self.select_page(bus, page)?;
+                bus.read_block(self.addr, com)
+            }
+        }
+    }
+
+    /// `format`, or the format the spec assigns to the command, adjusted to
+    /// what this device uses.
+    fn format(&self, com: u8, format: Option<DataFormat>) -> DataFormat {
+        let queried = self.capabilities.as_ref()
+            .and_then(|c| c.get(&com))
+            .and_then(|c| c.format);
+
+        match format.or_else(|| pmbus::command(com).map(|c| c.format)) {
+            Some(DataFormat::Linear11) if format.is_none() && queried == Some(QueryFormat::Direct) => DataFormat::Direct,
+            Some(DataFormat::Linear11) => self.linear_format,
+            Some(format) => format,
+            None => DataFormat::Raw,
+        }
+    }
+
+    /// MFR_POUT_MAX summed over the pages
+    pub fn output_rating(&self) -> Option<f64> {
+        self.ratings.iter()
+            .filter(|(_, rating, _)| rating.name == "pout_max")
+            .map(|(_, _, value)| *value)
+            .reduce(|a, b| a + b)
+    }
+
+    pub fn is_direct(&self, com: u8) -> bool {
+        self.format(com, None) == DataFormat::Direct
+    }
+
+    pub fn coefficients(&self, com: u8) -> Option<&Coefficients> {
+        self.coefficients.get(&com)
+    }
+
+    fn direct(&self, com: u8, bits: u16) -> PMBusResult<f64> {
+        match self.coefficients.get(&com) {
+            Some(c) => Ok(pmbus::direct(bits, c) as f64),
+            None => Err(PMBusError::Unsupported { com, what: "DIRECT format without coefficients" }),
+        }
+    }
+
+    fn vid(&self, com: u8, bits: u16, mode: u8) -> PMBusResult<f64> {
+        let table = self.vid_table.or_else(|| VidTable::from_vout_mode(mode))
+            .ok_or(PMBusError::Unsupported { com, what: "VID code table of VOUT_MODE" })?;
+
+        match pmbus::vid(bits, table) {
+            Some(volts) => Ok(volts as f64),
+            None => Err(PMBusError::InvalidData { com, what: "VID code outside of its table" }),
+        }
+    }
+
+    /// Read `com` of a single phase of `page`, PHASE is pointed back at all
+    /// phases afterwards so other bus masters are not surprised.
+    pub fn read_phase<T: PmbusTransport + ?Sized>(&mut self, bus: &mut T, page: Option<u8>, phase: Option<u8>, com: u8, format: Option<DataFormat>) -> PMBusResult<f64> {
+        let phase = match phase {
+            Some(phase) => phase,
+            None => return self.read(bus, page, com, format),
+        };
+
+        self.select_page(bus, page)?;
+        bus.write_byte(self.addr, pmbus::PHASE, phase)?;
+        self.phase_selected = true;
+
+        let value = self.read(bus, page, com, format);
+
+        let restored = bus.write_byte(self.addr, pmbus::PHASE, pmbus::ALL_PHASES);
+        self.phase_selected = restored.is_err();
+
+        let value = value?;
+        restored?;
+
+        Ok(value)
+    }
+
+    /// Read `com` on `page` and decode it as `format`, or the format the spec
+    /// assigns to the command when `format` is `None`.
+    pub fn read<T: PmbusTransport + ?Sized>(&mut self, bus: &mut T, page: Option<u8>, com: u8, format: Option<DataFormat>) -> PMBusResult<f64> {
+        let size = pmbus::command(com).map_or(Size::Word, |c| c.size);
+
+        match self.format(com, format) {
+            DataFormat::Raw => match size {
+                Size::Byte => Ok(self.read_byte(bus, page, com)? as f64),
+                Size::Word => Ok(self.read_word(bus, page, com)? as f64),
+                _ => Err(PMBusError::Unsupported { com, what: "raw value of a block command" }),
+            },
+            DataFormat::Linear11 => Ok(pmbus::linear11(self.read_word(bus, page, com)?) as f64),
+            DataFormat::ULinear16 => {
+                let mode = self.read_byte(bus, page, pmbus::VOUT_MODE)?;
+                let bits = self.read_word(bus, page, com)?;
+
+                match mode >> 5 {
+                    0b000 => Ok(pmbus::ulinear16(bits, mode) as f64),
+                    0b001 => self.vid(com, bits, mode),
+                    0b010 => self.direct(com, bits),
+                    0b011 => Ok(pmbus::half(bits) as f64),
+                    _ => Err(PMBusError::Unsupported { com, what: "VOUT_MODE" }),
+                }
+            }
+            DataFormat::Direct => {
+                let bits = self.read_word(bus, page, com)?;
+                self.direct(com, bits)
+            }
+            DataFormat::Vid => {
+                let mode = match self.vid_table {
+                    Some(_) => 0,
+                    None => self.read_byte(bus, page, pmbus::VOUT_MODE)?,
+                };
+                let bits = self.read_word(bus, page, com)?;
+                self.vid(com, bits, mode)
+            }
+            DataFormat::NonNumeric => Err(PMBusError::Unsupported { com, what: "non numeric command" }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::transport::MockBus;
+
+    const ADDR: u16 = 0x58;
+    const READ_VOUT: u8 = 0x8B;
+
+    fn config(text: &str) -> Config {
+        Config::parse(&format!("prefix = \"test\"\n{text}\n[[device]]\nmodule = \"1\"\naddress = 0x58")).unwrap()
+    }
+
+    const METRICS: &str = r#"
+        [[metric]]
+        name = "input_voltage"
+        help = "Input voltage"
+        command = "READ_VIN"
+
+        [[metric]]
+        name = "input_current"
+        help = "Input current"
+        command = "READ_IIN"
+    "#;
+
+    #[test]
+    fn reads_linear11_and_vout_mode() {
+        let config = config("");
+        let mut device = Device::new(&config.devices[0], "test");
+        let mut bus = MockBus::new();
+        bus.set_word(ADDR, pmbus::READ_VIN, 0xF1CC)
+            .set_byte(ADDR, pmbus::VOUT_MODE, 0x17)
+            .set_word(ADDR, READ_VOUT, 6144);
+
+        assert_eq!(device.read(&mut bus, None, pmbus::READ_VIN, None).unwrap(), 115.0);
+        assert_eq!(device.read(&mut bus, None, READ_VOUT, None).unwrap(), 12.0);
+        assert!(device.read(&mut bus, None, pmbus::READ_IIN, None).is_err());
+    }
+
+    #[test]
+    fn reads_configured_pages() {
+        let mut config = config("");
+        config.devices[0].pages = Some(vec![0, 1]);
+        let mut device = Device::new(&config.devices[0], "test");
+        let mut bus = MockBus::new();
+        for (page, vout) in [(0, 12.0), (1, 5.0)] {
+            bus.set_page(ADDR, page)
+                .set_byte(ADDR, pmbus::VOUT_MODE, 0x17)
+                .set_word(ADDR, READ_VOUT, (vout * 512.0) as u16);
+        }
+
+        assert_eq!(device.read(&mut bus, Some(1), READ_VOUT, None).unwrap(), 5.0);
+        assert_eq!(device.read(&mut bus, Some(0), READ_VOUT, None).unwrap(), 12.0);
+    }
+
+    #[test]
+    fn discovers_through_query_and_coefficients() {
+        let config = config(METRICS);
+        let mut device = Device::new(&config.devices[0], "test");
+        let mut bus = MockBus::new();
+        bus.set_process(ADDR, pmbus::QUERY, &[pmbus::QUERY], &[0xE0])
+            .set_process(ADDR, pmbus::QUERY, &[pmbus::READ_VIN], &[0xAC]) // readable, DIRECT
+            .set_process(ADDR, pmbus::COEFFICIENTS, &[pmbus::READ_VIN, 0x01], &[10, 0, 0, 0, 0])
+            .set_word(ADDR, pmbus::READ_VIN, 1150);
+
+        device.discover(&mut bus, config.device_metrics(&config.devices[0]), &[]);
+
+        assert!(device.supports(pmbus::READ_VIN));
+        assert!(!device.supports(pmbus::READ_IIN));
+        assert_eq!(device.coefficients(pmbus::READ_VIN), Some(&Coefficients { m: 10, b: 0, r: 0 }));
+        assert_eq!(device.read(&mut bus, None, pmbus::READ_VIN, None).unwrap(), 115.0);
+    }
+
+    #[test]
+    fn discovers_by_reading_without_query() {
+        let config = config(METRICS);
+        let mut device = Device::new(&config.devices[0], "test");
+        let mut bus = MockBus::new();
+        bus.set_linear11(ADDR, pmbus::READ_VIN, 115.0);
+
+        device.discover(&mut bus, config.device_metrics(&config.devices[0]), &[]);
+
+        assert!(device.pages.is_empty());
+        assert!(device.supports(pmbus::READ_VIN));
+        assert!(!device.supports(pmbus::READ_IIN));
+    }
+
+    #[test]
+    fn reads_vid_with_table_of_vout_mode() {
+        let mut config = config("");
+        let mut device = Device::new(&config.devices[0], "test");
+        let mut bus = MockBus::new();
+        bus.set_byte(ADDR, pmbus::VOUT_MODE, 0x22) // VID, VR13
+            .set_word(ADDR, READ_VOUT, 0x65);
+
+        assert_eq!(device.read(&mut bus, None, READ_VOUT, None).unwrap(), 1.5);
+
+        config.devices[0].vid = Some(VidTable::Vr12);
+        let mut device = Device::new(&config.devices[0], "test");
+        assert_eq!(device.read(&mut bus, None, READ_VOUT, None).unwrap(), 0.75);
+    }
+
+    #[test]
+    fn reads_half_precision_vout() {
+        let config = config("");
+        let mut device = Device::new(&config.devices[0], "test");
+        let mut bus = MockBus::new();
+        bus.set_byte(ADDR, pmbus::VOUT_MODE, 0x60)
+            .set_word(ADDR, READ_VOUT, 0x4A00);
+
+        assert_eq!(device.read(&mut bus, None, READ_VOUT, None).unwrap(), 12.0);
+    }
+
+    fn cml(bus: &mut MockBus) -> u8 {
+        bus.read_byte(ADDR, pmbus::STATUS_CML).unwrap()
+    }
+
+    #[test]
+    fn discovery_leaves_no_cml_faults() {
+        let config = config(METRICS);
+        let mut bus = MockBus::new();
+        bus.set_byte(ADDR, pmbus::STATUS_CML, 0x02) // other communication fault
+            .set_linear11(ADDR, pmbus::READ_VIN, 115.0);
+
+        let device = crate::discover(&config, &config.devices[0], "test", &mut bus);
+
+        assert!(device.pages.is_empty());
+        assert!(!device.supports(pmbus::READ_IIN));
+        assert_eq!(cml(&mut bus), 0x02);
+    }
+
+    #[test]
+    fn probes_pages_queried_as_writable() {
+        let config = config("");
+        let mut bus = MockBus::new();
+        for page in [0, 1] {
+            bus.set_page(ADDR, page)
+                .set_byte(ADDR, pmbus::STATUS_CML, 0x00)
+                .set_byte(ADDR, pmbus::STATUS_BYTE, 0x00);
+        }
+        bus.set_process(ADDR, pmbus::QUERY, &[pmbus::PAGE], &[0xF0]);
+
+        let mut device = crate::discover(&config, &config.devices[0], "test", &mut bus);
+
+        assert_eq!(device.pages, [0, 1]);
+        for page in [0, 1] {
+            assert_eq!(device.read_byte(&mut bus, Some(page), pmbus::STATUS_CML).unwrap(), 0x00);
+        }
+    }
+
+    #[test]
+    fn probes_pages_without_query() {
+        let config = config("");
+        let mut bus = MockBus::new();
+        for page in [0, 1, 2] {
+            bus.set_page(ADDR, page)
+                .set_byte(ADDR, pmbus::STATUS_CML, 0x00)
+                .set_byte(ADDR, pmbus::STATUS_BYTE, 0x00);
+        }
+
+        let mut device = crate::discover(&config, &config.devices[0], "test", &mut bus);
+
+        assert_eq!(device.pages, [0, 1, 2]);
+        for page in [0, 1, 2] {
+            assert_eq!(device.read_byte(&mut bus, Some(page), pmbus::STATUS_CML).unwrap(), 0x00);
+        }
+    }
+
+    #[test]
+    fn does_not_probe_pages_queried_as_read_only() {
+        let config = config("");
+        let mut bus = MockBus::new();
+        for page in [0, 1] {
+            bus.set_page(ADDR, page).set_byte(ADDR, pmbus::STATUS_BYTE, 0x00);
+        }
+        bus.set_page(ADDR, 1);
+        bus.set_process(ADDR, pmbus::QUERY, &[pmbus::PAGE], &[0xA0]);
+
+        let mut device = Device::new(&config.devices[0], "test");
+        device.discover(&mut bus, std::iter::empty(), &[]);
+
+        assert!(device.pages.is_empty());
+        assert_eq!(bus.read_byte(ADDR, pmbus::PAGE).unwrap(), 1);
+    }
+
+    #[test]
+    fn keeps_a_single_configured_page() {
+        let mut config = config("");
+        config.devices[0].pages = Some(vec![2]);
+        let mut device = Device::new(&config.devices[0], "test");
+        let mut bus = MockBus::new();
+        for (page, vout) in [(0, 12.0), (2, 5.0)] {
+            bus.set_page(ADDR, page)
+                .set_byte(ADDR, pmbus::VOUT_MODE, 0x17)
+                .set_word(ADDR, READ_VOUT, (vout * 512.0) as u16);
+        }
+        bus.set_page(ADDR, 0);
+
+        device.discover(&mut bus, std::iter::empty(), &[READ_VOUT]);
+
+        assert_eq!(device.read_pages(), [Some(2)]);
+        assert_eq!(device.read(&mut bus, Some(2), READ_VOUT, None).unwrap(), 5.0);
+    }
+
+    /// Transfers as they go over the bus
+    #[derive(Debug, PartialEq)]
+    enum Transfer {
+        Read(u8),
+        Write(u8, u8),
+        Process(u8, Vec<u8>),
+    }
+
+    #[derive(Default)]
+    struct RecordingBus {
+        bus: MockBus,
+        transfers: Vec<Transfer>,
+    }
+
+    impl PmbusTransport for RecordingBus {
+        fn read_byte(&mut self, addr: u16, com: u8) -> PMBusResult<u8> {
+            self.transfers.push(Transfer::Read(com));
+            self.bus.read_byte(addr, com)
+        }
+
+        fn read_word(&mut self, addr: u16, com: u8) -> PMBusResult<u16> {
+            self.transfers.push(Transfer::Read(com));
+            self.bus.read_word(addr, com)
+        }
+
+        fn read_block(&mut self, addr: u16, com: u8) -> PMBusResult<Vec<u8>> {
+            self.transfers.push(Transfer::Read(com));
+            self.bus.read_block(addr, com)
+        }
+
+        fn write_byte(&mut self, addr: u16, com: u8, val: u8) -> PMBusResult<()> {
+            self.transfers.push(Transfer::Write(com, val));
+            self.bus.write_byte(addr, com, val)
+        }
+
+        fn write_word(&mut self, addr: u16, com: u8, val: u16) -> PMBusResult<()> {
+            self.bus.write_word(addr, com, val)
+        }
+
+        fn write_block(&mut self, addr: u16, com: u8, vals: &[u8]) -> PMBusResult<()> {
+            self.bus.write_block(addr, com, vals)
+        }
+
+        fn process_block(&mut self, addr: u16, com: u8, vals: &[u8]) -> PMBusResult<Vec<u8>> {
+            self.transfers.push(Transfer::Process(com, vals.to_vec()));
+            self.bus.process_block(addr, com, vals)
+        }
+    }
+
+    #[test]
+    fn reads_a_phase_between_selecting_and_restoring_it() {
+        const READ_IOUT: u8 = 0x8C;
+        let mut config = config("");
+        config.devices[0].phases = 2;
+        let mut device = Device::new(&config.devices[0], "test");
+        device.pages = vec![0, 1];
+        device.page_plus_read = true;
+        let mut bus = RecordingBus::default();
+        for page in [0, 1] {
+            bus.bus.set_page(ADDR, page).set_linear11(ADDR, READ_IOUT, 20.0);
+        }
+
+        assert_eq!(device.read_phase(&mut bus, Some(1), Some(0), READ_IOUT, None).unwrap(), 20.0);
+        assert_eq!(bus.transfers, [
+            Transfer::Write(pmbus::PAGE, 1),
+            Transfer::Write(pmbus::PHASE, 0),
+            Transfer::Read(READ_IOUT),
+            Transfer::Write(pmbus::PHASE, pmbus::ALL_PHASES),
+        ]);
+
+        // the phase is restored, PAGE_PLUS_READ is back for the totals
+        bus.transfers.clear();
+        device.read(&mut bus, Some(0), READ_IOUT, None).unwrap();
+        assert_eq!(bus.transfers, [Transfer::Process(pmbus::PAGE_PLUS_READ, vec![0, READ_IOUT])]);
+    }
+
+    #[test]
+    fn read_only_discovery_only_reads() {
+        let mut config = config(METRICS);
+        config.devices[0].read_only = true;
+        let mut bus = MockBus::new();
+        for page in [0, 1] {
+            bus.set_page(ADDR, page)
+                .set_byte(ADDR, pmbus::STATUS_CML, 0x00)
+                .set_linear11(ADDR, pmbus::READ_VIN, 115.0);
+        }
+        bus.set_process(ADDR, pmbus::QUERY, &[pmbus::PAGE], &[0xF0])
+            .set_process(ADDR, pmbus::QUERY, &[pmbus::QUERY], &[0xE0])
+            .set_process(ADDR, pmbus::QUERY, &[pmbus::READ_VIN], &[0xA0]);
+
+        let device = crate::discover(&config, &config.devices[0], "test", &mut bus);
+
+        assert!(device.pages.is_empty());
+        assert_eq!(bus.read_byte(ADDR, pmbus::PAGE).unwrap(), 1);
+        // found by reading, not QUERY
+        assert_eq!(device.capabilities.as_ref().unwrap()[&pmbus::READ_VIN].format, None);
+        assert!(!device.supports(pmbus::READ_IIN));
+        assert!(!device.supports(pmbus::STATUS_CML));
+        assert!(device.info.is_none());
+    }
+}
@@ -0,0 +1,76 @@
+use crate::device::Device;
+use crate::pmbus;
+use crate::transport::{PMBusError, PMBusResult, PmbusTransport};
+
+pub const MFR_EFFICIENCY_LL: u8 = 0xAA;
+pub const MFR_EFFICIENCY_HL: u8 = 0xAB;
+
+/// Efficiency at three output powers for one input voltage, from
+/// MFR_EFFICIENCY_LL (low line) or MFR_EFFICIENCY_HL (high line)
+#[derive(Clone, Copy, Debug)]
+pub struct EfficiencyCurve {
+    pub vin: f64,
+    /// `(watts, ratio)` at light, medium and high load
+    pub points: [(f64, f64); 3],
+}
+
+impl EfficiencyCurve {
+    /// Input voltage followed by power and efficiency (%) at light, medium
+    /// and high load, 7 LINEAR11 words
+    fn parse(com: u8, data: &[u8]) -> PMBusResult<Self> {
+        if data.len() < 14 {
+            return Err(PMBusError::InvalidData { com, what: "short efficiency curve" });
+        }
+
+        let word = |i: usize| pmbus::linear11(u16::from_le_bytes([data[2 * i], data[2 * i + 1]])) as f64;
+        let mut points = [(word(1), word(2) / 100.0), (word(3), word(4) / 100.0), (word(5), word(6) / 100.0)];
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        Ok(EfficiencyCurve { vin: word(0), points })
+    }
+
+    /// Efficiency at `watts`, interpolated linearly between the points and
+    /// held at the outer ones beyond them
+    pub fn at(&self, watts: f64) -> f64 {
+        let [first, .., last] = self.points;
+        if watts <= first.0 {
+            return first.1;
+        }
+
+        for pair in self.points.windows(2) {
+            let ((w0, e0), (w1, e1)) = (pair[0], pair[1]);
+            if watts <= w1 && w1 > w0 {
+                return e0 + (e1 - e0) * (watts - w0) / (w1 - w0);
+            }
+        }
+
+        last.1
+    }
+}
+
+/// Curves of the device that could be read, low line first
+pub fn read_curves<T: PmbusTransport + ?Sized>(device: &mut Device, bus: &mut T) -> Vec<EfficiencyCurve> {
+    let mut curves = Vec::new();
+    for com in [MFR_EFFICIENCY_LL, MFR_EFFICIENCY_HL] {
+        if !device.supports(com) {
+            continue;
+        }
+        if let Ok(curve) = device.read_block(bus, None, com).and_then(|data| EfficiencyCurve::parse(com, &data)) {
+            curves.push(curve);
+        }
+    }
+
+    curves
+}
+
+/// Efficiency the curve closest to `vin` promises at `pout`, `vin` is only
+/// needed to pick between several curves
+pub fn expected(curves: &[EfficiencyCurve], vin: Option<f64>, pout: f64) -> Option<f64> {
+    let curve = match (curves, vin) {
+        ([curve], _) => curve,
+        (_, Some(vin)) => curves.iter().min_by(|a, b| (a.vin - vin).abs().total_cmp(&(b.vin - vin).abs()))?,
+        _ => return None,
+    };
+
+    Some(curve.at(pout))
+}
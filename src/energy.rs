@@ -0,0 +1,190 @@
+use crate::device::Device;
+use crate::pmbus;
+use crate::transport::{PMBusError, PMBusResult, PmbusTransport};
+use std::time::Instant;
+
+/// The accumulator rolls over into the rollover count past 0x7FFF
+const ACCUMULATOR_ROLLOVER: u32 = 0x8000;
+/// Accumulator and rollover count together wrap at 2^23
+const ENERGY_WRAP: u32 = ACCUMULATOR_ROLLOVER << 8;
+/// The sample count is 24 bits
+const SAMPLES_WRAP: u32 = 1 << 24;
+
+/// `(energy command, power command)` pairs, the accumulator sums readings of
+/// the power command
+pub const COMMANDS: [(u8, u8); 2] = [
+    (pmbus::READ_EIN, pmbus::READ_PIN),
+    (pmbus::READ_EOUT, pmbus::READ_POUT),
+];
+
+/// One READ_EIN/READ_EOUT reading
+#[derive(Clone, Copy, Debug)]
+pub struct EnergySample {
+    /// Accumulator including its rollovers
+    energy: u32,
+    samples: u32,
+    time: Instant,
+}
+
+impl EnergySample {
+    /// Accumulator (2 bytes), rollover count (1 byte) and sample count
+    /// (3 bytes), all little endian
+    fn parse(com: u8, data: &[u8]) -> PMBusResult<Self> {
+        if data.len() < 6 {
+            return Err(PMBusError::InvalidData { com, what: "short energy accumulator" });
+        }
+
+        let accumulator = u16::from_le_bytes([data[0], data[1]]) as u32;
+        if accumulator >= ACCUMULATOR_ROLLOVER {
+            return Err(PMBusError::InvalidData { com, what: "energy accumulator above 0x7FFF" });
+        }
+
+        Ok(EnergySample {
+            energy: data[2] as u32 * ACCUMULATOR_ROLLOVER + accumulator,
+            samples: u32::from_le_bytes([data[3], data[4], data[5], 0]),
+            time: Instant::now(),
+        })
+    }
+
+    /// Accumulator increase per sample since `previous`, both counts may
+    /// have wrapped once. `None` when no sample was taken in between.
+    fn average_since(&self, previous: &EnergySample) -> Option<f64> {
+        let energy = self.energy.wrapping_sub(previous.energy) % ENERGY_WRAP;
+        let samples = self.samples.wrapping_sub(previous.samples) % SAMPLES_WRAP;
+
+        match samples {
+            0 => None,
+            _ => Some(energy as f64 / samples as f64),
+        }
+    }
+}
+
+/// Joules accumulated by `com` (READ_EIN or READ_EOUT) of `page` since the
+/// previous call, 0 on the first one. The average power over the interval is
+/// the accumulator increase over the sample count increase, decoded like a
+/// reading of `power` (READ_PIN or READ_POUT): with the DIRECT coefficients of
+/// the energy command (or the power command), otherwise with the LINEAR11
+/// exponent the power command currently reports.
+pub fn read_joules<T: PmbusTransport + ?Sized>(device: &mut Device, bus: &mut T, page: Option<u8>, com: u8, power: u8) -> PMBusResult<f64> {
+    let data = device.read_block(bus, page, com)?;
+    let sample = EnergySample::parse(com, &data)?;
+
+    let previous = match device.energy.insert((page, com), sample) {
+        Some(previous) => previous,
+        None => return Ok(0.0),
+    };
+
+    let average = match sample.average_since(&previous) {
+        Some(average) => average,
+        None => return Ok(0.0),
+    };
+
+    let watts = match device.is_direct(power) {
+        true => {
+            let c = device.coefficients(com).or_else(|| device.coefficients(power))
+                .ok_or(PMBusError::Unsupported { com, what: "DIRECT format without coefficients" })?;
+            pmbus::direct_value(average, c)
+        }
+        false => {
+            let bits = device.read_word(bus, page, power)?;
+            let exp = pmbus::twos_comp((bits & 0xF800) >> 11, 5);
+            average * 2_f64.powi(exp as i32)
+        }
+    };
+
+    Ok(watts.max(0.0) * sample.time.duration_since(previous.time).as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::transport::MockBus;
+    use std::time::Duration;
+
+    const ADDR: u16 = 0x58;
+
+    /// Accumulator, rollover count and sample count as they are on the wire
+    fn block(accumulator: u16, rollovers: u8, samples: u32) -> Vec<u8> {
+        let mut data = accumulator.to_le_bytes().to_vec();
+        data.push(rollovers);
+        data.extend(&samples.to_le_bytes()[..3]);
+        data
+    }
+
+    fn sample(accumulator: u16, rollovers: u8, samples: u32) -> EnergySample {
+        EnergySample::parse(pmbus::READ_EIN, &block(accumulator, rollovers, samples)).unwrap()
+    }
+
+    #[test]
+    fn parses_the_block() {
+        let s = sample(0x1234, 2, 0x0A0B0C);
+        assert_eq!(s.energy, 2 * 0x8000 + 0x1234);
+        assert_eq!(s.samples, 0x0A0B0C);
+
+        assert!(EnergySample::parse(pmbus::READ_EIN, &block(0x8000, 0, 0)).is_err());
+        assert!(EnergySample::parse(pmbus::READ_EIN, &[0, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn averages_without_wrap() {
+        assert_eq!(sample(1100, 0, 20).average_since(&sample(100, 0, 10)), Some(100.0));
+        assert_eq!(sample(1100, 0, 10).average_since(&sample(100, 0, 10)), None);
+    }
+
+    #[test]
+    fn averages_across_accumulator_rollover() {
+        // 0x7F00 -> 0x8000 + 0x0100
+        assert_eq!(sample(0x0100, 1, 2).average_since(&sample(0x7F00, 0, 0)), Some(256.0));
+    }
+
+    #[test]
+    fn averages_across_rollover_count_wrap() {
+        // 0xFF * 0x8000 + 0x7F00 -> 2^23 + 0x0100
+        assert_eq!(sample(0x0100, 0, 4).average_since(&sample(0x7F00, 0xFF, 0)), Some(128.0));
+    }
+
+    #[test]
+    fn averages_across_sample_count_wrap() {
+        assert_eq!(sample(1000, 0, 0x000002).average_since(&sample(600, 0, 0xFFFFFE)), Some(100.0));
+    }
+
+    fn device(text: &str) -> Device {
+        let config = Config::parse(&format!("prefix = \"test\"\n[[device]]\nmodule = \"1\"\naddress = 0x58\n{text}")).unwrap();
+        Device::new(&config.devices[0], "test")
+    }
+
+    /// Make the last reading of READ_EIN a zero one taken a second ago
+    fn read_a_second_ago(device: &mut Device) {
+        let time = Instant::now() - Duration::from_secs(1);
+        device.energy.insert((None, pmbus::READ_EIN), EnergySample { energy: 0, samples: 0, time });
+    }
+
+    #[test]
+    fn joules_of_linear11_power() {
+        let mut device = device("");
+        let mut bus = MockBus::new();
+        bus.set(ADDR, pmbus::READ_EIN, &block(1450, 0, 10))
+            .set_word(ADDR, pmbus::READ_PIN, 0xF1CC); // 2^-2
+
+        assert_eq!(read_joules(&mut device, &mut bus, None, pmbus::READ_EIN, pmbus::READ_PIN).unwrap(), 0.0);
+
+        read_a_second_ago(&mut device);
+        let joules = read_joules(&mut device, &mut bus, None, pmbus::READ_EIN, pmbus::READ_PIN).unwrap();
+        assert!((36.25..40.0).contains(&joules), "{joules}");
+    }
+
+    #[test]
+    fn joules_of_direct_power() {
+        let mut device = device(r#"
+            format = "direct"
+            coefficients = { READ_PIN = { m = 10, b = 0, R = 0 } }
+        "#);
+        let mut bus = MockBus::new();
+        bus.set(ADDR, pmbus::READ_EIN, &block(1450, 0, 10));
+
+        read_a_second_ago(&mut device);
+        let joules = read_joules(&mut device, &mut bus, None, pmbus::READ_EIN, pmbus::READ_PIN).unwrap();
+        assert!((14.5..16.0).contains(&joules), "{joules}");
+    }
+}
@@ -0,0 +1,66 @@
+use crate::device::Device;
+use crate::transport::PmbusTransport;
+
+/// `(command, first fan)`, each register configures two fans
+pub const FAN_CONFIGS: [(u8, u8); 2] = [
+    (0x3A, 1), // FAN_CONFIG_1_2
+    (0x3D, 3), // FAN_CONFIG_3_4
+];
+
+/// Setup of an installed fan
+#[derive(Clone, Copy, Debug)]
+pub struct FanConfig {
+    pub fan: u8,
+    /// FAN_COMMAND_n is in rpm rather than duty cycle (%)
+    pub rpm: bool,
+    pub tach_pulses: u8,
+}
+
+impl FanConfig {
+    /// Installed (bit 3), rpm (bit 2) and tach pulses - 1 (bits 0-1) of the
+    /// low nibble of a FAN_CONFIG register
+    fn parse(fan: u8, nibble: u8) -> Option<Self> {
+        match nibble & 0x8 != 0 {
+            true => Some(FanConfig { fan, rpm: nibble & 0x4 != 0, tach_pulses: (nibble & 0x3) + 1 }),
+            false => None,
+        }
+    }
+
+    pub fn unit(&self) -> &'static str {
+        match self.rpm {
+            true => "rpm",
+            false => "duty",
+        }
+    }
+}
+
+/// Commands read for the fan setup
+pub fn commands() -> impl Iterator<Item = u8> {
+    FAN_CONFIGS.iter().map(|&(com, _)| com)
+}
+
+/// `(page, fan)` of every installed fan FAN_CONFIG_1_2/3_4 report
+pub fn read_fan_configs<T: PmbusTransport + ?Sized>(device: &mut Device, bus: &mut T) -> Vec<(Option<u8>, FanConfig)> {
+    let mut fans = Vec::new();
+
+    for page in device.read_pages() {
+        for (com, first) in FAN_CONFIGS {
+            if !device.supports(com) {
+                continue;
+            }
+            let config = match device.read_byte(bus, page, com) {
+                Ok(config) => config,
+                Err(_) => continue,
+            };
+
+            // the first fan is in the high nibble
+            for (fan, nibble) in [(first, config >> 4), (first + 1, config & 0xF)] {
+                if let Some(fan) = FanConfig::parse(fan, nibble) {
+                    fans.push((page, fan));
+                }
+            }
+        }
+    }
+
+    fans
+}
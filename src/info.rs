@@ -0,0 +1,39 @@
+use crate::device::Device;
+use crate::pmbus;
+use crate::transport::PmbusTransport;
+
+/// `(command, label)` of the inventory strings, in label order
+pub const INFO: [(u8, &str); 6] = [
+    (0x99, "manufacturer"),
+    (0x9A, "model"),
+    (0x9B, "revision"),
+    (0x9E, "serial"),
+    (0x9D, "date"),
+    (0x9C, "location"),
+];
+
+/// Commands read for the inventory
+pub fn commands() -> impl Iterator<Item = u8> {
+    INFO.iter().map(|&(com, _)| com)
+}
+
+/// Label names of the inventory, in `INFO` order
+pub fn labels() -> impl Iterator<Item = &'static str> {
+    INFO.iter().map(|&(_, label)| label)
+}
+
+/// The MFR_* strings of the device in `INFO` order, empty for the ones it
+/// does not have. `None` when it has none of them.
+pub fn read_info<T: PmbusTransport + ?Sized>(device: &mut Device, bus: &mut T) -> Option<Vec<String>> {
+    let info: Vec<String> = INFO.iter()
+        .map(|&(com, _)| match device.supports(com) {
+            true => device.read_block(bus, None, com).map(|data| pmbus::string(&data)).unwrap_or_default(),
+            false => String::new(),
+        })
+        .collect();
+
+    match info.iter().all(String::is_empty) {
+        true => None,
+        false => Some(info),
+    }
+}
@@ -0,0 +1,67 @@
+use crate::device::Device;
+use crate::transport::{PmbusTransport, ReadLog};
+
+/// A fault or warning limit register, `common` ones apply to the input and
+/// are read on the first page only like the input readings.
+pub struct Limit {
+    pub code: u8,
+    pub name: &'static str,
+    pub common: bool,
+}
+
+const fn limit(code: u8, name: &'static str, common: bool) -> Limit {
+    Limit { code, name, common }
+}
+
+// PMBus Specification Part II, revision 1.3, section 15
+pub const LIMITS: &[Limit] = &[
+    limit(0x40, "vout_ov_fault", false),
+    limit(0x42, "vout_ov_warn", false),
+    limit(0x43, "vout_uv_warn", false),
+    limit(0x44, "vout_uv_fault", false),
+    limit(0x46, "iout_oc_fault", false),
+    limit(0x48, "iout_oc_lv_fault", false),
+    limit(0x4A, "iout_oc_warn", false),
+    limit(0x4B, "iout_uc_fault", false),
+    limit(0x4F, "ot_fault", false),
+    limit(0x51, "ot_warn", false),
+    limit(0x52, "ut_warn", false),
+    limit(0x53, "ut_fault", false),
+    limit(0x55, "vin_ov_fault", true),
+    limit(0x57, "vin_ov_warn", true),
+    limit(0x58, "vin_uv_warn", true),
+    limit(0x59, "vin_uv_fault", true),
+    limit(0x5B, "iin_oc_fault", true),
+    limit(0x5D, "iin_oc_warn", true),
+    limit(0x68, "pout_op_fault", false),
+    limit(0x6A, "pout_op_warn", false),
+    limit(0x6B, "pin_op_warn", true),
+];
+
+/// Commands read for the limits
+pub fn commands() -> impl Iterator<Item = u8> {
+    LIMITS.iter().map(|l| l.code)
+}
+
+/// `(page, limit, value)` of every limit the device supports and could be
+/// read, decoded like the readings they apply to.
+pub fn read_limits<T: PmbusTransport + ?Sized>(device: &mut Device, bus: &mut T, log: &mut ReadLog) -> Vec<(Option<u8>, &'static str, f64)> {
+    let mut limits = Vec::new();
+
+    let pages = device.read_pages();
+    let supported: Vec<&Limit> = LIMITS.iter().filter(|l| device.supports(l.code)).collect();
+    for limit in supported {
+        let pages = match limit.common {
+            true => &pages[..1],
+            false => &pages[..],
+        };
+
+        for &page in pages {
+            if let Some(value) = log.record(limit.code, device.read(bus, page, limit.code, None)) {
+                limits.push((page.filter(|_| !limit.common), limit.name, value));
+            }
+        }
+    }
+
+    limits
+}
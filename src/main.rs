@@ -1,74 +1,29 @@
 use clap::{crate_authors, crate_name, crate_version, Arg};
-use i2cdev::core::*;
-use i2cdev::linux::{LinuxI2CDevice, LinuxI2CError};
-use prometheus_exporter::{self, prometheus::register_gauge_vec, prometheus::register_int_gauge_vec};
-use std::env;
+use prometheus::Registry;
+use std::collections::HashMap;
 use std::net::IpAddr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-type PMBusResult<T> = Result<T, LinuxI2CError>;
-
-// https://gist.github.com/otya128/1784473224a80f3ae453e8667b5fe8e5
-
-const MOD2_ADDR: u16 = 0x59;
-const MOD1_ADDR: u16 = 0x58;
-const ATX_ADDR: u16 = 0x25;
-const FRAME_ADDR: u16 = 0x56;
-const PREFIX: &str = "fsp_twins_exporter";
-
-const PAGE_CMD: u8 = 0x00;
-const IVOLT_CMD: u8 = 0x88;
-const OVOLT_EXP_CMD: u8 = 0x20;
-const OVOLT_MANT_CMD: u8 = 0x8B;
-const TEMP1_CMD: u8 = 0x8D;
-const TEMP2_CMD: u8 = 0x8E;
-const ICUR_CMD: u8 = 0x89;
-const OCUR_CMD: u8 = 0x8C;
-const IPOW_CMD: u8 = 0x97;
-const OPOW_CMD: u8 = 0x96;
-const FAN_SPEED_CMD: u8 = 0x90;
-
-pub fn read_byte(dev: &str, addr: u16, com: u8) -> PMBusResult<u8> {
-    let mut dev = LinuxI2CDevice::new(dev, addr)?;
-    dev.set_smbus_pec(true)?;
-
-    return dev.smbus_read_byte_data(com);
-}
-
-pub fn read_word(dev: &str, addr: u16, com: u8) -> PMBusResult<u16> {
-    let mut dev = LinuxI2CDevice::new(dev, addr)?;
-    dev.set_smbus_pec(true)?;
-
-    return dev.smbus_read_word_data(com);
-}
-
-pub fn read_linear11(dev: &str, addr: u16, com: u8) -> PMBusResult<f32> {
-    let mut dev = LinuxI2CDevice::new(dev, addr)?;
-    dev.set_smbus_pec(true)?;
-
-    let bits = dev.smbus_read_word_data(com)?;
-    let exp = twos_comp((bits & 0xF800) >> 11, 5);  // high 5 bits
-    let mant = twos_comp(bits & 0x7FF, 11);         // low 11 bits
-
-    return Ok(mant as f32 * 2_f32.powi(exp as i32));
-}
+mod collect;
+mod config;
+mod device;
+mod efficiency;
+mod energy;
+mod fans;
+mod info;
+mod limits;
+mod pmbus;
+mod ratings;
+mod server;
+mod status;
+mod transport;
 
-pub fn read_linear16(dev: &str, addr: u16, mant_com: u8, exp_com: u8) -> PMBusResult<f32> {
-    let mut dev = LinuxI2CDevice::new(dev, addr)?;
-    dev.set_smbus_pec(true)?;
-
-    let mant = dev.smbus_read_word_data(mant_com)?;
-    let exp = twos_comp(dev.smbus_read_byte_data(exp_com)? as u16, 5);
-
-    return Ok((mant as f32) * 2_f32.powi(exp as i32));
-}
-
-pub fn twos_comp(val: u16, bits: usize) -> i16 {
-    if val & (1<<(bits-1)) != 0 {
-        ((val as i32) - (1_i32<<bits)) as i16
-    } else {
-        val as i16
-    }
-}
+use collect::{Gauges, Snapshot};
+use config::{Config, DeviceConfig};
+use device::Device;
+use transport::{LinuxBus, MockBus, PMBusResult, PmbusTransport};
 
 fn main() -> PMBusResult<()> {
     let matches = clap::Command::new(crate_name!())
@@ -95,87 +50,241 @@ fn main() -> PMBusResult<()> {
         .arg(
             Arg::new("device")
                 .env("PROMETHEUS_PMBUS_EXPORTER_DEVICE")
-                .help("ic2 device to listen on")
+                .help("ic2 device to listen on, used for config devices without a bus")
                 .takes_value(true),
         )
+        .arg(
+            Arg::new("interval")
+                .short('i')
+                .long("interval")
+                .env("PROMETHEUS_PMBUS_EXPORTER_INTERVAL")
+                .help("seconds between polls of the devices, scrapes are served from the last one, 0 polls on every scrape")
+                .default_value("10")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("config")
+                .short('c')
+                .long("config")
+                .env("PROMETHEUS_PMBUS_EXPORTER_CONFIG")
+                .help("device configuration (toml), defaults to two FSP Twins modules")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("simulate")
+                .long("simulate")
+                .env("PROMETHEUS_PMBUS_EXPORTER_SIMULATE")
+                .help("serve readings from an in-memory FSP Twins register map instead of i2c")
+                .takes_value(false),
+        )
         .get_matches();
 
-    let dev = matches.value_of("device").expect("device is required");
+    let config = Config::load(matches.value_of("config")).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    });
+
+    let simulate = matches.is_present("simulate");
+    let default_bus = match matches.value_of("device") {
+        Some(dev) => Some(dev),
+        None if simulate => Some("simulated"),
+        None => None,
+    };
+
+    let mut buses: HashMap<&str, Box<dyn PmbusTransport + Send>> = HashMap::new();
+    let mut devices = Vec::new();
+    let mut device_configs = Vec::new();
+    for device_config in &config.devices {
+        let bus_name = device_config.bus.as_deref().or(default_bus).unwrap_or_else(|| {
+            eprintln!("device {} has no bus and no device was given", device_config.module);
+            std::process::exit(1);
+        });
+        let bus = buses.entry(bus_name).or_insert_with(|| match simulate {
+            true => Box::new(MockBus::fsp_twins()),
+            false => Box::new(LinuxBus::new(bus_name)),
+        });
+
+        let device = discover(&config, device_config, bus_name, &mut **bus);
+        if device_config.optional && device.capabilities.is_none() {
+            eprintln!("{bus_name}@{:#04x}: no answer, leaving out optional device {}", device.addr, device.module);
+            continue;
+        }
+        devices.push(device);
+        device_configs.push(device_config);
+    }
 
     let port = matches.value_of("port").unwrap();
     let port = port.parse::<u16>().expect("port must be a valid number");
     let addr = matches.value_of("addr").unwrap().parse::<IpAddr>().unwrap();
     let bind = (addr, port).into();
+    let interval = matches.value_of("interval").unwrap();
+    let interval = Duration::from_secs(interval.parse::<u64>().expect("interval must be a valid number"));
 
-    let exporter = prometheus_exporter::start(bind).unwrap();
-    println!("listening on http://{bind}/metrics");
+    let registry = Registry::new();
+    let gauges = Gauges::register(&config, &registry);
+    gauges.set_discovered(&devices);
 
-    let rpm_gauge   = register_int_gauge_vec!(format!("{PREFIX}_fan_rpm"),	"Speed of the fan",				&["bus", "module"]).unwrap();
-    let ivolt_gauge = register_gauge_vec!(format!("{PREFIX}_input_voltage"),	"Input voltage from outlet",			&["bus", "module"]).unwrap();
-    let icur_gauge  = register_gauge_vec!(format!("{PREFIX}_input_current"),	"Input current (amp) from outlet",		&["bus", "module"]).unwrap();
-    let ipow_gauge  = register_gauge_vec!(format!("{PREFIX}_input_power"),	"Power (W) being drawn from outlet",		&["bus", "module"]).unwrap();
-    let ovolt_gauge = register_gauge_vec!(format!("{PREFIX}_output_voltage"),	"Voltage provided to PSU",			&["bus", "module"]).unwrap();
-    let ocur_gauge  = register_gauge_vec!(format!("{PREFIX}_output_current"),	"Current (amp) provided to the main PSU",	&["bus", "module"]).unwrap();
-    let opow_gauge  = register_gauge_vec!(format!("{PREFIX}_output_power"),	"Power (W) being drawn by the PSU",		&["bus", "module"]).unwrap();
-    let temp_gauge  = register_gauge_vec!(format!("{PREFIX}_temperature"),	"Temperature",					&["bus", "module", "sensor"]).unwrap();
+    let snapshot = Snapshot::default();
+
+    // without an interval the polls are driven by the scrapes
+    let on_demand = interval.is_zero();
+    let (poll_tx, poll_rx) = mpsc::sync_channel(1);
+    server::start(bind, snapshot.clone(), on_demand.then_some(poll_tx)).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    });
+    println!("listening on http://{bind}/metrics");
 
     loop {
-        // Will block until a new request comes in.
-        rpm_gauge.reset();
-        ivolt_gauge.reset();
-        icur_gauge.reset();
-        ipow_gauge.reset();
-        ovolt_gauge.reset();
-        ocur_gauge.reset();
-        opow_gauge.reset();
-        temp_gauge.reset();
-
-        let _guard = exporter.wait_request();
-
-        for module in &["1", "2"] {
-            let mod_addr = match module {
-                &"1" => MOD1_ADDR,
-                &"2" => MOD2_ADDR,
-                _ => continue,
-            };
-            rpm_gauge
-                .with_label_values(&[dev, &module])
-                .set(read_word(&dev, mod_addr, FAN_SPEED_CMD)? as i64);
-
-            ivolt_gauge
-                .with_label_values(&[dev, &module])
-                .set(read_linear16(&dev, MOD1_ADDR, IVOLT_CMD, OVOLT_EXP_CMD)? as f64);
-
-            icur_gauge
-                .with_label_values(&[dev, &module])
-                .set(read_linear11(&dev, MOD1_ADDR, ICUR_CMD)? as f64);
-
-            ipow_gauge
-                .with_label_values(&[dev, &module])
-                .set(read_linear11(&dev, MOD1_ADDR, IPOW_CMD)? as f64);
-
-            ovolt_gauge
-                .with_label_values(&[dev, &module])
-                .set(read_linear16(&dev, MOD1_ADDR, OVOLT_MANT_CMD, OVOLT_EXP_CMD)? as f64);
-
-            ocur_gauge
-                .with_label_values(&[dev, &module])
-                .set(read_linear11(&dev, MOD1_ADDR, OCUR_CMD)? as f64);
-
-            opow_gauge
-                .with_label_values(&[dev, &module])
-                .set(read_linear11(&dev, MOD1_ADDR, OPOW_CMD)? as f64);
-
-            for temp_sensor in &["1", "2"] {
-                let temp_cmd = match temp_sensor {
-                    &"1" => TEMP1_CMD,
-                    &"2" => TEMP2_CMD,
-                    _ => continue,
-                };
-                temp_gauge
-                    .with_label_values(&[dev, &module, &temp_sensor])
-                    .set(read_linear11(&dev, MOD1_ADDR, temp_cmd)? as f64);
+        let started = Instant::now();
+        gauges.reset();
+
+        // one thread per bus, the devices of a bus are still read one at a time
+        let mut by_bus: HashMap<String, Vec<(&mut Device, &DeviceConfig)>> = HashMap::new();
+        for (device, &device_config) in devices.iter_mut().zip(&device_configs) {
+            by_bus.entry(device.bus.clone()).or_default().push((device, device_config));
+        }
+        let changed = thread::scope(|scope| {
+            let polls: Vec<_> = buses
+                .iter_mut()
+                .map(|(&bus_name, bus)| {
+                    let bus_devices = by_bus.remove(bus_name).unwrap_or_default();
+                    let (config, gauges) = (&config, &gauges);
+                    scope.spawn(move || poll_bus(config, &mut **bus, bus_devices, gauges))
+                })
+                .collect();
+            // no short circuit, every thread has to be joined
+            polls.into_iter().fold(false, |changed, poll| poll.join().unwrap() | changed)
+        });
+
+        collect::set_groups(&devices, &gauges);
+
+        if changed {
+            gauges.set_discovered(&devices);
+        }
+
+        snapshot.set(registry.gather());
+        match on_demand {
+            true => poll_rx.recv().unwrap(),
+            false => thread::sleep(interval.saturating_sub(started.elapsed())),
+        }
+    }
+}
+
+/// Probe and read the devices of one bus, true if one was inserted or removed.
+fn poll_bus<T: PmbusTransport + ?Sized>(config: &Config, bus: &mut T, devices: Vec<(&mut Device, &DeviceConfig)>, gauges: &Gauges) -> bool {
+    let mut changed = false;
+    for (device, device_config) in devices {
+        let present = device.probe(bus);
+        if present != device.present {
+            let at = format!("{}@{:#04x}", device.bus, device.addr);
+            match present {
+                true => {
+                    eprintln!("{at}: module {} inserted", device.module);
+                    // it may well be a different model
+                    *device = discover(config, device_config, &device.bus.clone(), bus);
+                }
+                false => eprintln!("{at}: module {} removed", device.module),
             }
+            device.present = present;
+            changed = true;
+        }
+
+        gauges.set_present(device, present);
+        if present {
+            collect::collect_device(config, device_config, device, bus, gauges);
         }
     }
+    changed
+}
+
+/// Set up `device_config` on `bus`: find what it supports and read what does
+/// not change while it is plugged in. The STATUS_CML bits set by probing
+/// are cleared again so they are not exported as faults.
+fn discover<T: PmbusTransport + ?Sized>(config: &Config, device_config: &DeviceConfig, bus_name: &str, bus: &mut T) -> Device {
+    let mut device = Device::new(device_config, bus_name);
+    let cml = device.read_cml(bus);
+
+    let mut extra: Vec<u8> = Vec::new();
+    if device_config.status {
+        extra.extend(status::commands());
+    }
+    if device_config.energy {
+        extra.extend(energy::COMMANDS.map(|(com, _)| com));
+    }
+    if device_config.limits {
+        extra.extend(limits::commands());
+    }
+    extra.extend(info::commands());
+    extra.extend(ratings::commands());
+    extra.extend([efficiency::MFR_EFFICIENCY_LL, efficiency::MFR_EFFICIENCY_HL]);
+    extra.extend(fans::commands());
+
+    device.discover(bus, config.device_metrics(device_config), &extra);
+    device.present = device.probe(bus);
+    device.info = info::read_info(&mut device, bus);
+    device.ratings = ratings::read_ratings(&mut device, bus);
+    device.efficiency = efficiency::read_curves(&mut device, bus);
+    device.fans = fans::read_fan_configs(&mut device, bus);
+    device.clear_cml(bus, cml);
+
+    device
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reinserted_module_is_rediscovered_without_cml_faults() {
+        let config = Config::load(None).unwrap();
+        let registry = Registry::new();
+        let gauges = Gauges::register(&config, &registry);
+        let mut bus = MockBus::fsp_twins();
+
+        // not present when the exporter started
+        let device_config = &config.devices[0];
+        let mut device = Device::new(device_config, "test");
+        assert!(poll_bus(&config, &mut bus, vec![(&mut device, device_config)], &gauges));
+
+        assert!(device.present);
+        assert!(device.capabilities.is_some());
+        assert!(device.info.is_some());
+        assert_eq!(bus.read_byte(device.addr, pmbus::STATUS_CML).unwrap(), 0x00);
+        assert!(!poll_bus(&config, &mut bus, vec![(&mut device, device_config)], &gauges));
+    }
+
+    #[test]
+    fn read_only_device_without_status_byte_is_present() {
+        let config = Config::parse(r#"
+            prefix = "test"
+
+            [[metric]]
+            name = "input_voltage"
+            help = "Input voltage"
+            command = "READ_VIN"
+
+            [[device]]
+            module = "frame"
+            address = 0x56
+            read_only = true
+        "#).unwrap();
+        let registry = Registry::new();
+        let gauges = Gauges::register(&config, &registry);
+        let mut bus = MockBus::new();
+        bus.set_linear11(0x56, 0x88, 115.0);  // READ_VIN, no STATUS_BYTE
+
+        let device_config = &config.devices[0];
+        let mut device = discover(&config, device_config, "test", &mut bus);
+        assert!(device.present);
+        poll_bus(&config, &mut bus, vec![(&mut device, device_config)], &gauges);
+
+        let families = registry.gather();
+        let value = |name: &str| families.iter()
+            .find(|family| family.get_name() == name)
+            .map(|family| family.get_metric()[0].get_gauge().get_value());
+        assert_eq!(value("test_up"), Some(1.0));
+        assert_eq!(value("test_module_present"), Some(1.0));
+        assert_eq!(value("test_input_voltage"), Some(115.0));
+    }
 }
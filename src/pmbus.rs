@@ -0,0 +1,527 @@
+use crate::transport::{PMBusError, PMBusResult, PmbusTransport};
+use serde::Deserialize;
+
+pub const PAGE: u8 = 0x00;
+pub const PHASE: u8 = 0x04;
+pub const PAGE_PLUS_READ: u8 = 0x06;
+pub const STATUS_BYTE: u8 = 0x78;
+pub const STATUS_CML: u8 = 0x7E;
+pub const VOUT_MODE: u8 = 0x20;
+pub const QUERY: u8 = 0x1A;
+pub const COEFFICIENTS: u8 = 0x30;
+pub const READ_EIN: u8 = 0x86;
+pub const READ_EOUT: u8 = 0x87;
+pub const READ_VIN: u8 = 0x88;
+pub const READ_IIN: u8 = 0x89;
+pub const READ_POUT: u8 = 0x96;
+pub const READ_PIN: u8 = 0x97;
+
+/// PHASE value addressing every phase at once
+pub const ALL_PHASES: u8 = 0xFF;
+
+/// How the value of a command is encoded on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DataFormat {
+    /// Byte or word read as an unsigned integer
+    Raw,
+    /// 5 bit exponent, 11 bit mantissa
+    Linear11,
+    /// Output voltage commands, encoding selected by VOUT_MODE (ULINEAR16,
+    /// VID, DIRECT or IEEE half precision)
+    #[serde(alias = "linear16")]
+    ULinear16,
+    /// Y = (mX + b) * 10^R
+    Direct,
+    /// Voltage identification code
+    Vid,
+    /// Not a number (strings, status bits, block payloads)
+    NonNumeric,
+}
+
+/// DIRECT format coefficients, Y = (mX + b) * 10^R
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Coefficients {
+    pub m: i16,
+    pub b: i16,
+    #[serde(alias = "R")]
+    pub r: i8,
+}
+
+/// VID code tables, named like the linux pmbus driver does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VidTable {
+    /// 6.25mV steps down from 1.6V
+    Vr11,
+    /// 5mV steps up from 0.25V (VR12, VR13 5mV, IMVP8)
+    Vr12,
+    /// 10mV steps up from 0.5V (VR12.5, VR13 10mV)
+    Vr13,
+    /// 10mV steps up from 0.2V
+    Imvp9,
+    /// 6.25mV steps down from 1.55V
+    Amd625mv,
+}
+
+impl VidTable {
+    /// Table selected by the parameter bits of VOUT_MODE. The spec leaves the
+    /// encoding to the manufacturer, these are the values used by TI and
+    /// Infineon controllers.
+    pub fn from_vout_mode(mode: u8) -> Option<Self> {
+        match mode & 0x1F {
+            0x01 => Some(VidTable::Vr12),
+            0x02 => Some(VidTable::Vr13),
+            0x03 => Some(VidTable::Imvp9),
+            0x04 => Some(VidTable::Vr13),
+            0x05 => Some(VidTable::Vr12),
+            0x07 => Some(VidTable::Vr12),
+            _ => None,
+        }
+    }
+}
+
+/// Data format reported by QUERY
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryFormat {
+    Linear,
+    Signed16,
+    Direct,
+    Unsigned8,
+    Vid,
+    Manufacturer,
+    NonNumeric,
+}
+
+impl QueryFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            QueryFormat::Linear => "linear",
+            QueryFormat::Signed16 => "signed16",
+            QueryFormat::Direct => "direct",
+            QueryFormat::Unsigned8 => "unsigned8",
+            QueryFormat::Vid => "vid",
+            QueryFormat::Manufacturer => "manufacturer",
+            QueryFormat::NonNumeric => "non_numeric",
+        }
+    }
+}
+
+/// What a device supports of a command, `format` is only known from QUERY
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub read: bool,
+    pub write: bool,
+    pub format: Option<QueryFormat>,
+}
+
+impl Capability {
+    pub fn access(&self) -> &'static str {
+        match (self.read, self.write) {
+            (true, true) => "rw",
+            (true, false) => "r",
+            (false, true) => "w",
+            (false, false) => "",
+        }
+    }
+}
+
+/// SMBus transaction used to read a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Size {
+    /// Send byte, there is no data
+    Send,
+    Byte,
+    Word,
+    Block,
+}
+
+#[derive(Debug)]
+pub struct Command {
+    pub code: u8,
+    pub name: &'static str,
+    pub size: Size,
+    pub format: DataFormat,
+}
+
+macro_rules! commands {
+    ($($code:literal $name:ident $size:ident $format:ident,)*) => {
+        pub const COMMANDS: &[Command] = &[
+            $(Command { code: $code, name: stringify!($name), size: Size::$size, format: DataFormat::$format },)*
+        ];
+    };
+}
+
+// PMBus Specification Part II, revision 1.3, Appendix I
+commands! {
+    0x00 PAGE                       Byte  Raw,
+    0x01 OPERATION                  Byte  NonNumeric,
+    0x02 ON_OFF_CONFIG              Byte  NonNumeric,
+    0x03 CLEAR_FAULTS               Send  NonNumeric,
+    0x04 PHASE                      Byte  Raw,
+    0x05 PAGE_PLUS_WRITE            Block NonNumeric,
+    0x06 PAGE_PLUS_READ             Block NonNumeric,
+    0x10 WRITE_PROTECT              Byte  NonNumeric,
+    0x11 STORE_DEFAULT_ALL          Send  NonNumeric,
+    0x12 RESTORE_DEFAULT_ALL        Send  NonNumeric,
+    0x13 STORE_DEFAULT_CODE         Byte  NonNumeric,
+    0x14 RESTORE_DEFAULT_CODE       Byte  NonNumeric,
+    0x15 STORE_USER_ALL             Send  NonNumeric,
+    0x16 RESTORE_USER_ALL           Send  NonNumeric,
+    0x17 STORE_USER_CODE            Byte  NonNumeric,
+    0x18 RESTORE_USER_CODE          Byte  NonNumeric,
+    0x19 CAPABILITY                 Byte  NonNumeric,
+    0x1A QUERY                      Block NonNumeric,
+    0x1B SMBALERT_MASK              Word  NonNumeric,
+    0x20 VOUT_MODE                  Byte  NonNumeric,
+    0x21 VOUT_COMMAND               Word  ULinear16,
+    0x22 VOUT_TRIM                  Word  ULinear16,
+    0x23 VOUT_CAL_OFFSET            Word  ULinear16,
+    0x24 VOUT_MAX                   Word  ULinear16,
+    0x25 VOUT_MARGIN_HIGH           Word  ULinear16,
+    0x26 VOUT_MARGIN_LOW            Word  ULinear16,
+    0x27 VOUT_TRANSITION_RATE       Word  Linear11,
+    0x28 VOUT_DROOP                 Word  Linear11,
+    0x29 VOUT_SCALE_LOOP            Word  Linear11,
+    0x2A VOUT_SCALE_MONITOR         Word  Linear11,
+    0x2B VOUT_MIN                   Word  ULinear16,
+    0x30 COEFFICIENTS               Block NonNumeric,
+    0x31 POUT_MAX                   Word  Linear11,
+    0x32 MAX_DUTY                   Word  Linear11,
+    0x33 FREQUENCY_SWITCH           Word  Linear11,
+    0x34 POWER_MODE                 Byte  NonNumeric,
+    0x35 VIN_ON                     Word  Linear11,
+    0x36 VIN_OFF                    Word  Linear11,
+    0x37 INTERLEAVE                 Word  NonNumeric,
+    0x38 IOUT_CAL_GAIN              Word  Linear11,
+    0x39 IOUT_CAL_OFFSET            Word  Linear11,
+    0x3A FAN_CONFIG_1_2             Byte  NonNumeric,
+    0x3B FAN_COMMAND_1              Word  Linear11,
+    0x3C FAN_COMMAND_2              Word  Linear11,
+    0x3D FAN_CONFIG_3_4             Byte  NonNumeric,
+    0x3E FAN_COMMAND_3              Word  Linear11,
+    0x3F FAN_COMMAND_4              Word  Linear11,
+    0x40 VOUT_OV_FAULT_LIMIT        Word  ULinear16,
+    0x41 VOUT_OV_FAULT_RESPONSE     Byte  NonNumeric,
+    0x42 VOUT_OV_WARN_LIMIT         Word  ULinear16,
+    0x43 VOUT_UV_WARN_LIMIT         Word  ULinear16,
+    0x44 VOUT_UV_FAULT_LIMIT        Word  ULinear16,
+    0x45 VOUT_UV_FAULT_RESPONSE     Byte  NonNumeric,
+    0x46 IOUT_OC_FAULT_LIMIT        Word  Linear11,
+    0x47 IOUT_OC_FAULT_RESPONSE     Byte  NonNumeric,
+    0x48 IOUT_OC_LV_FAULT_LIMIT     Word  ULinear16,
+    0x49 IOUT_OC_LV_FAULT_RESPONSE  Byte  NonNumeric,
+    0x4A IOUT_OC_WARN_LIMIT         Word  Linear11,
+    0x4B IOUT_UC_FAULT_LIMIT        Word  Linear11,
+    0x4C IOUT_UC_FAULT_RESPONSE     Byte  NonNumeric,
+    0x4F OT_FAULT_LIMIT             Word  Linear11,
+    0x50 OT_FAULT_RESPONSE          Byte  NonNumeric,
+    0x51 OT_WARN_LIMIT              Word  Linear11,
+    0x52 UT_WARN_LIMIT              Word  Linear11,
+    0x53 UT_FAULT_LIMIT             Word  Linear11,
+    0x54 UT_FAULT_RESPONSE          Byte  NonNumeric,
+    0x55 VIN_OV_FAULT_LIMIT         Word  Linear11,
+    0x56 VIN_OV_FAULT_RESPONSE      Byte  NonNumeric,
+    0x57 VIN_OV_WARN_LIMIT          Word  Linear11,
+    0x58 VIN_UV_WARN_LIMIT          Word  Linear11,
+    0x59 VIN_UV_FAULT_LIMIT         Word  Linear11,
+    0x5A VIN_UV_FAULT_RESPONSE      Byte  NonNumeric,
+    0x5B IIN_OC_FAULT_LIMIT         Word  Linear11,
+    0x5C IIN_OC_FAULT_RESPONSE      Byte  NonNumeric,
+    0x5D IIN_OC_WARN_LIMIT          Word  Linear11,
+    0x5E POWER_GOOD_ON              Word  ULinear16,
+    0x5F POWER_GOOD_OFF             Word  ULinear16,
+    0x60 TON_DELAY                  Word  Linear11,
+    0x61 TON_RISE                   Word  Linear11,
+    0x62 TON_MAX_FAULT_LIMIT        Word  Linear11,
+    0x63 TON_MAX_FAULT_RESPONSE     Byte  NonNumeric,
+    0x64 TOFF_DELAY                 Word  Linear11,
+    0x65 TOFF_FALL                  Word  Linear11,
+    0x66 TOFF_MAX_WARN_LIMIT        Word  Linear11,
+    0x68 POUT_OP_FAULT_LIMIT        Word  Linear11,
+    0x69 POUT_OP_FAULT_RESPONSE     Byte  NonNumeric,
+    0x6A POUT_OP_WARN_LIMIT         Word  Linear11,
+    0x6B PIN_OP_WARN_LIMIT          Word  Linear11,
+    0x78 STATUS_BYTE                Byte  NonNumeric,
+    0x79 STATUS_WORD                Word  NonNumeric,
+    0x7A STATUS_VOUT                Byte  NonNumeric,
+    0x7B STATUS_IOUT                Byte  NonNumeric,
+    0x7C STATUS_INPUT               Byte  NonNumeric,
+    0x7D STATUS_TEMPERATURE         Byte  NonNumeric,
+    0x7E STATUS_CML                 Byte  NonNumeric,
+    0x7F STATUS_OTHER               Byte  NonNumeric,
+    0x80 STATUS_MFR_SPECIFIC        Byte  NonNumeric,
+    0x81 STATUS_FANS_1_2            Byte  NonNumeric,
+    0x82 STATUS_FANS_3_4            Byte  NonNumeric,
+    0x86 READ_EIN                   Block NonNumeric,
+    0x87 READ_EOUT                  Block NonNumeric,
+    0x88 READ_VIN                   Word  Linear11,
+    0x89 READ_IIN                   Word  Linear11,
+    0x8A READ_VCAP                  Word  Linear11,
+    0x8B READ_VOUT                  Word  ULinear16,
+    0x8C READ_IOUT                  Word  Linear11,
+    0x8D READ_TEMPERATURE_1         Word  Linear11,
+    0x8E READ_TEMPERATURE_2         Word  Linear11,
+    0x8F READ_TEMPERATURE_3         Word  Linear11,
+    0x90 READ_FAN_SPEED_1           Word  Linear11,
+    0x91 READ_FAN_SPEED_2           Word  Linear11,
+    0x92 READ_FAN_SPEED_3           Word  Linear11,
+    0x93 READ_FAN_SPEED_4           Word  Linear11,
+    0x94 READ_DUTY_CYCLE            Word  Linear11,
+    0x95 READ_FREQUENCY             Word  Linear11,
+    0x96 READ_POUT                  Word  Linear11,
+    0x97 READ_PIN                   Word  Linear11,
+    0x98 PMBUS_REVISION             Byte  NonNumeric,
+    0x99 MFR_ID                     Block NonNumeric,
+    0x9A MFR_MODEL                  Block NonNumeric,
+    0x9B MFR_REVISION               Block NonNumeric,
+    0x9C MFR_LOCATION               Block NonNumeric,
+    0x9D MFR_DATE                   Block NonNumeric,
+    0x9E MFR_SERIAL                 Block NonNumeric,
+    0x9F APP_PROFILE_SUPPORT        Block NonNumeric,
+    0xA0 MFR_VIN_MIN                Word  Linear11,
+    0xA1 MFR_VIN_MAX                Word  Linear11,
+    0xA2 MFR_IIN_MAX                Word  Linear11,
+    0xA3 MFR_PIN_MAX                Word  Linear11,
+    0xA4 MFR_VOUT_MIN               Word  ULinear16,
+    0xA5 MFR_VOUT_MAX               Word  ULinear16,
+    0xA6 MFR_IOUT_MAX               Word  Linear11,
+    0xA7 MFR_POUT_MAX               Word  Linear11,
+    0xA8 MFR_TAMBIENT_MAX           Word  Linear11,
+    0xA9 MFR_TAMBIENT_MIN           Word  Linear11,
+    0xAA MFR_EFFICIENCY_LL          Block NonNumeric,
+    0xAB MFR_EFFICIENCY_HL          Block NonNumeric,
+    0xAC MFR_PIN_ACCURACY           Byte  NonNumeric,
+    0xAD IC_DEVICE_ID               Block NonNumeric,
+    0xAE IC_DEVICE_REV              Block NonNumeric,
+    0xC0 MFR_MAX_TEMP_1             Word  Linear11,
+    0xC1 MFR_MAX_TEMP_2             Word  Linear11,
+    0xC2 MFR_MAX_TEMP_3             Word  Linear11,
+}
+
+pub fn command(code: u8) -> Option<&'static Command> {
+    COMMANDS.iter().find(|c| c.code == code)
+}
+
+pub fn lookup(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|c| c.name.eq_ignore_ascii_case(name))
+}
+
+/// Ask the device for the DIRECT coefficients it uses when reporting `com`
+pub fn read_coefficients<T: PmbusTransport + ?Sized>(bus: &mut T, addr: u16, com: u8) -> PMBusResult<Coefficients> {
+    // second byte selects the coefficients used for reading (1) or writing (0)
+    let data = bus.process_block(addr, COEFFICIENTS, &[com, 0x01])?;
+    if data.len() < 5 {
+        return Err(PMBusError::InvalidData { com: COEFFICIENTS, what: "short COEFFICIENTS response" });
+    }
+
+    Ok(Coefficients {
+        m: i16::from_le_bytes([data[0], data[1]]),
+        b: i16::from_le_bytes([data[2], data[3]]),
+        r: data[4] as i8,
+    })
+}
+
+/// Ask the device whether it supports `com`, `None` when it does not
+pub fn query<T: PmbusTransport + ?Sized>(bus: &mut T, addr: u16, com: u8) -> PMBusResult<Option<Capability>> {
+    let data = bus.process_block(addr, QUERY, &[com])?;
+    let bits = *data.first().ok_or(PMBusError::InvalidData { com: QUERY, what: "empty QUERY response" })?;
+    if bits & 0x80 == 0 {
+        return Ok(None);
+    }
+
+    let format = match (bits >> 2) & 0b111 {
+        0b000 => QueryFormat::Linear,
+        0b001 => QueryFormat::Signed16,
+        0b011 => QueryFormat::Direct,
+        0b100 => QueryFormat::Unsigned8,
+        0b101 => QueryFormat::Vid,
+        0b110 => QueryFormat::Manufacturer,
+        _ => QueryFormat::NonNumeric,
+    };
+
+    Ok(Some(Capability {
+        read: bits & 0x20 != 0,
+        write: bits & 0x40 != 0,
+        format: Some(format),
+    }))
+}
+
+pub fn linear11(bits: u16) -> f32 {
+    let exp = twos_comp((bits & 0xF800) >> 11, 5);  // high 5 bits
+    let mant = twos_comp(bits & 0x7FF, 11);         // low 11 bits
+
+    mant as f32 * 2_f32.powi(exp as i32)
+}
+
+/// Unsigned mantissa, exponent in the low 5 bits of VOUT_MODE
+pub fn ulinear16(mant: u16, mode: u8) -> f32 {
+    let exp = twos_comp((mode & 0x1F) as u16, 5);
+
+    (mant as f32) * 2_f32.powi(exp as i32)
+}
+
+/// X = (Y * 10^-R - b) / m
+pub fn direct(bits: u16, c: &Coefficients) -> f32 {
+    direct_value(bits as i16 as f64, c) as f32
+}
+
+/// DIRECT decoding of an already combined value (e.g. an average)
+pub fn direct_value(y: f64, c: &Coefficients) -> f64 {
+    (y * 10_f64.powi(-(c.r as i32)) - c.b as f64) / c.m as f64
+}
+
+/// IEEE 754 half precision, VOUT_MODE 0b011 since PMBus 1.3
+pub fn half(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exp = ((bits >> 10) & 0x1F) as i32;
+    let frac = (bits & 0x3FF) as f32;
+
+    match exp {
+        0 => sign * frac * 2_f32.powi(-24), // subnormal
+        0x1F if frac == 0.0 => sign * f32::INFINITY,
+        0x1F => f32::NAN,
+        _ => sign * (1.0 + frac / 1024.0) * 2_f32.powi(exp - 15),
+    }
+}
+
+/// Volts for VID `code`, `None` when the code is off (0) or outside the table
+pub fn vid(code: u16, table: VidTable) -> Option<f32> {
+    let mv = match (table, code) {
+        (VidTable::Vr11, 0x02..=0xB2) => 1600.0 - (code - 2) as f32 * 6.25,
+        (VidTable::Vr12, 0x01..) => 250.0 + (code - 1) as f32 * 5.0,
+        (VidTable::Vr13, 0x01..) => 500.0 + (code - 1) as f32 * 10.0,
+        (VidTable::Imvp9, 0x01..) => 200.0 + (code - 1) as f32 * 10.0,
+        (VidTable::Amd625mv, 0x00..=0xD8) => 1550.0 - code as f32 * 6.25,
+        _ => return None,
+    };
+
+    Some(mv / 1000.0)
+}
+
+/// Text of a block read (MFR_ID, ...), without the padding devices leave
+/// around it
+pub fn string(data: &[u8]) -> String {
+    let text: String = data.iter()
+        .map(|&b| match b {
+            0x20..=0x7E => b as char,
+            _ => ' ',
+        })
+        .collect();
+
+    text.trim().to_string()
+}
+
+pub fn twos_comp(val: u16, bits: usize) -> i16 {
+    if val & (1<<(bits-1)) != 0 {
+        ((val as i32) - (1_i32<<bits)) as i16
+    } else {
+        val as i16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn linear11_known_answers() {
+        assert_eq!(linear11(0xF1CC), 115.0); // 460 * 2^-2
+        assert_eq!(linear11(0x0801), 2.0);
+        assert_eq!(linear11(0x07FF), -1.0);
+        assert_eq!(linear11(0xE800), 0.0);
+    }
+
+    #[test]
+    fn ulinear16_uses_vout_mode_exponent() {
+        assert_eq!(ulinear16(6144, 0x17), 12.0); // 2^-9
+        assert_eq!(ulinear16(3, 0x01), 6.0);
+    }
+
+    #[test]
+    fn twos_comp_sign_extends() {
+        assert_eq!(twos_comp(0x1F, 5), -1);
+        assert_eq!(twos_comp(0x10, 5), -16);
+        assert_eq!(twos_comp(0x0F, 5), 15);
+        assert_eq!(twos_comp(0x400, 11), -1024);
+    }
+
+    #[test]
+    fn direct_known_answers() {
+        let unity = Coefficients { m: 1, b: 0, r: 0 };
+        assert_eq!(direct(100, &unity), 100.0);
+        assert_eq!(direct(0xFFF6, &unity), -10.0); // Y is signed
+
+        // Y = (20 * 12.5 - 100) * 10^-1
+        let c = Coefficients { m: 20, b: -100, r: -1 };
+        assert_eq!(direct(15, &c), 12.5);
+
+        let c = Coefficients { m: 19599, b: 0, r: -2 };
+        assert!((direct(22549, &c) - 115.05).abs() < 0.01);
+    }
+
+    #[test]
+    fn direct_value_of_averages() {
+        let c = Coefficients { m: 1, b: 0, r: -1 };
+        assert_eq!(direct_value(7.5, &c), 75.0);
+    }
+
+    #[test]
+    fn vid_known_answers() {
+        assert_eq!(vid(0x02, VidTable::Vr11), Some(1.6));
+        assert_eq!(vid(0xB2, VidTable::Vr11), Some(0.5));
+        assert_eq!(vid(0x01, VidTable::Vr11), None);
+        assert_eq!(vid(0xB3, VidTable::Vr11), None);
+
+        assert_eq!(vid(0x01, VidTable::Vr12), Some(0.25));
+        assert_eq!(vid(0xFF, VidTable::Vr12), Some(1.52));
+        assert_eq!(vid(0x00, VidTable::Vr12), None); // off
+
+        assert_eq!(vid(0x01, VidTable::Vr13), Some(0.5));
+        assert_eq!(vid(0x65, VidTable::Vr13), Some(1.5));
+        assert_eq!(vid(0x01, VidTable::Imvp9), Some(0.2));
+
+        assert_eq!(vid(0x00, VidTable::Amd625mv), Some(1.55));
+        assert_eq!(vid(0xD8, VidTable::Amd625mv), Some(0.2));
+        assert_eq!(vid(0xD9, VidTable::Amd625mv), None);
+    }
+
+    #[test]
+    fn vid_table_from_vout_mode() {
+        assert_eq!(VidTable::from_vout_mode(0x21), Some(VidTable::Vr12));
+        assert_eq!(VidTable::from_vout_mode(0x22), Some(VidTable::Vr13));
+        assert_eq!(VidTable::from_vout_mode(0x23), Some(VidTable::Imvp9));
+        assert_eq!(VidTable::from_vout_mode(0x24), Some(VidTable::Vr13));
+        assert_eq!(VidTable::from_vout_mode(0x25), Some(VidTable::Vr12));
+        assert_eq!(VidTable::from_vout_mode(0x27), Some(VidTable::Vr12));
+        assert_eq!(VidTable::from_vout_mode(0x20), None);
+        assert_eq!(VidTable::from_vout_mode(0x26), None);
+    }
+
+    #[test]
+    fn half_known_answers() {
+        assert_eq!(half(0x3C00), 1.0);
+        assert_eq!(half(0xC000), -2.0);
+        assert_eq!(half(0x4A00), 12.0);
+        assert_eq!(half(0x7BFF), 65504.0); // largest normal
+        assert_eq!(half(0x0400), 2_f32.powi(-14)); // smallest normal
+    }
+
+    #[test]
+    fn half_subnormals_and_zero() {
+        assert_eq!(half(0x0001), 2_f32.powi(-24));
+        assert_eq!(half(0x03FF), 1023.0 * 2_f32.powi(-24));
+        assert_eq!(half(0x8001), -(2_f32.powi(-24)));
+        assert_eq!(half(0x0000), 0.0);
+        assert!(half(0x8000) == 0.0 && half(0x8000).is_sign_negative());
+    }
+
+    #[test]
+    fn half_infinities_and_nan() {
+        assert_eq!(half(0x7C00), f32::INFINITY);
+        assert_eq!(half(0xFC00), f32::NEG_INFINITY);
+        assert!(half(0x7E00).is_nan());
+        assert!(half(0xFC01).is_nan());
+    }
+}
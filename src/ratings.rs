@@ -0,0 +1,57 @@
+use crate::device::Device;
+use crate::transport::PmbusTransport;
+
+/// A MFR_* rating, `reading` is the command whose value is compared to it
+/// for the utilization
+pub struct Rating {
+    pub code: u8,
+    pub name: &'static str,
+    pub common: bool,
+    pub reading: Option<u8>,
+}
+
+const fn rating(code: u8, name: &'static str, common: bool, reading: Option<u8>) -> Rating {
+    Rating { code, name, common, reading }
+}
+
+// PMBus Specification Part II, revision 1.3, section 22.2
+pub const RATINGS: &[Rating] = &[
+    rating(0xA0, "vin_min", true, None),
+    rating(0xA1, "vin_max", true, None),
+    rating(0xA2, "iin_max", true, Some(0x89)),   // READ_IIN
+    rating(0xA3, "pin_max", true, Some(0x97)),   // READ_PIN
+    rating(0xA4, "vout_min", false, None),
+    rating(0xA5, "vout_max", false, None),
+    rating(0xA6, "iout_max", false, Some(0x8C)), // READ_IOUT
+    rating(0xA7, "pout_max", false, Some(0x96)), // READ_POUT
+    rating(0xA8, "tambient_max", true, None),
+    rating(0xA9, "tambient_min", true, None),
+];
+
+/// Commands read for the ratings
+pub fn commands() -> impl Iterator<Item = u8> {
+    RATINGS.iter().map(|r| r.code)
+}
+
+/// `(page, rating, value)` of every rating the device has, `page` being
+/// `None` for the common ones. Ratings that can not be read are left out.
+pub fn read_ratings<T: PmbusTransport + ?Sized>(device: &mut Device, bus: &mut T) -> Vec<(Option<u8>, &'static Rating, f64)> {
+    let mut ratings = Vec::new();
+
+    let pages = device.read_pages();
+    let supported: Vec<&'static Rating> = RATINGS.iter().filter(|r| device.supports(r.code)).collect();
+    for rating in supported {
+        let pages = match rating.common {
+            true => &pages[..1],
+            false => &pages[..],
+        };
+
+        for &page in pages {
+            if let Ok(value) = device.read(bus, page, rating.code, None) {
+                ratings.push((page.filter(|_| !rating.common), rating, value));
+            }
+        }
+    }
+
+    ratings
+}
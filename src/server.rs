@@ -0,0 +1,104 @@
+use crate::collect::Snapshot;
+use prometheus::{Encoder, TextEncoder};
+use std::net::SocketAddr;
+use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tiny_http::{Header, Request, Response, Server, StatusCode};
+
+const ENDPOINT: &str = "/metrics";
+
+/// Sent by Prometheus with every scrape
+const TIMEOUT_HEADER: &str = "X-Prometheus-Scrape-Timeout-Seconds";
+/// Prometheus' default `scrape_timeout`, for scrapers not sending the header
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Left of the scrape timeout to encode and send the answer, at most
+const MARGIN: Duration = Duration::from_millis(500);
+
+/// Scrapes answered at the same time, more wait until one is done
+const WORKERS: usize = 4;
+
+/// Serve `snapshot` on `bind`. With `poll` every scrape asks for a poll and
+/// waits for it as long as its scrape timeout allows, falling back to the
+/// last completed one.
+pub fn start(bind: SocketAddr, snapshot: Snapshot, poll: Option<SyncSender<()>>) -> Result<(), String> {
+    let server = Arc::new(Server::http(bind).map_err(|e| format!("can not listen on {bind}: {e}"))?);
+
+    // a scrape waiting on a poll must not hold up the others
+    for _ in 0..WORKERS {
+        let server = server.clone();
+        let snapshot = snapshot.clone();
+        let poll = poll.clone();
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                if let Err(e) = handle(request, &snapshot, poll.as_ref()) {
+                    eprintln!("failed to answer scrape: {e}");
+                }
+            }
+        });
+    }
+
+    Ok(())
+}
+
+fn handle(request: Request, snapshot: &Snapshot, poll: Option<&SyncSender<()>>) -> std::io::Result<()> {
+    if request.url() != ENDPOINT {
+        let response = Response::from_string(format!("try {ENDPOINT} for metrics\n"))
+            .with_status_code(StatusCode(301))
+            .with_header(Header::from_bytes("Location", ENDPOINT).unwrap());
+        return request.respond(response);
+    }
+
+    if let Some(poll) = poll {
+        let generation = snapshot.generation();
+        // full when a poll is already asked for, that one will do
+        let _ = poll.try_send(());
+        let timeout = scrape_timeout(&request);
+        if !snapshot.wait_after(generation, timeout - MARGIN.min(timeout / 10)) {
+            eprintln!("poll did not finish within the scrape timeout, serving the previous one");
+        }
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder.encode(&snapshot.families(), &mut buffer).unwrap();
+    let response = Response::from_data(buffer).with_header(Header::from_bytes("Content-Type", encoder.format_type()).unwrap());
+    request.respond(response)
+}
+
+/// How long the scraper waits for an answer
+fn scrape_timeout(request: &Request) -> Duration {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv(TIMEOUT_HEADER))
+        .and_then(|h| h.value.as_str().parse::<f64>().ok())
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .filter(|timeout| !timeout.is_zero())
+        .unwrap_or(DEFAULT_TIMEOUT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tiny_http::TestRequest;
+
+    fn timeout(value: &str) -> Duration {
+        let header = Header::from_bytes(TIMEOUT_HEADER, value).unwrap();
+        scrape_timeout(&TestRequest::new().with_path(ENDPOINT).with_header(header).into())
+    }
+
+    #[test]
+    fn scrape_timeout_from_header() {
+        assert_eq!(timeout("2.5"), Duration::from_millis(2500));
+        assert_eq!(scrape_timeout(&TestRequest::new().into()), DEFAULT_TIMEOUT);
+    }
+
+    #[test]
+    fn scrape_timeout_falls_back_on_bad_values() {
+        for value in ["1e20", "inf", "NaN", "-1", "0", "soon"] {
+            assert_eq!(timeout(value), DEFAULT_TIMEOUT, "{value}");
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use crate::device::Device;
+use crate::pmbus::Size;
+use crate::transport::{PmbusTransport, ReadLog};
+
+/// A status register and the name of every bit, bit 0 first. Reserved bits
+/// are left empty and not exported.
+pub struct StatusRegister {
+    pub code: u8,
+    pub name: &'static str,
+    pub size: Size,
+    pub bits: &'static [&'static str],
+}
+
+// PMBus Specification Part II, revision 1.3, section 17
+pub const STATUS_REGISTERS: &[StatusRegister] = &[
+    StatusRegister {
+        code: 0x79,
+        name: "status_word",
+        size: Size::Word,
+        bits: &[
+            "none_of_the_above", "cml", "temperature", "vin_uv_fault",
+            "iout_oc_fault", "vout_ov_fault", "off", "busy",
+            "unknown", "other", "fans", "power_good_negated",
+            "mfr_specific", "input", "iout_pout", "vout",
+        ],
+    },
+    StatusRegister {
+        code: 0x7A,
+        name: "status_vout",
+        size: Size::Byte,
+        bits: &[
+            "vout_tracking_error", "toff_max_warning", "ton_max_fault", "vout_max_min_warning",
+            "vout_uv_fault", "vout_uv_warning", "vout_ov_warning", "vout_ov_fault",
+        ],
+    },
+    StatusRegister {
+        code: 0x7B,
+        name: "status_iout",
+        size: Size::Byte,
+        bits: &[
+            "pout_op_warning", "pout_op_fault", "power_limiting", "current_share_fault",
+            "iout_uc_fault", "iout_oc_warning", "iout_oc_lv_fault", "iout_oc_fault",
+        ],
+    },
+    StatusRegister {
+        code: 0x7C,
+        name: "status_input",
+        size: Size::Byte,
+        bits: &[
+            "pin_op_warning", "iin_oc_warning", "iin_oc_fault", "unit_off_low_vin",
+            "vin_uv_fault", "vin_uv_warning", "vin_ov_warning", "vin_ov_fault",
+        ],
+    },
+    StatusRegister {
+        code: 0x7D,
+        name: "status_temperature",
+        size: Size::Byte,
+        bits: &[
+            "", "", "", "",
+            "ut_fault", "ut_warning", "ot_warning", "ot_fault",
+        ],
+    },
+    StatusRegister {
+        code: 0x7E,
+        name: "status_cml",
+        size: Size::Byte,
+        bits: &[
+            "other_memory_logic_fault", "other_communication_fault", "", "processor_fault",
+            "memory_fault", "pec_failed", "invalid_data", "invalid_command",
+        ],
+    },
+    StatusRegister {
+        code: 0x7F,
+        name: "status_other",
+        size: Size::Byte,
+        bits: &[
+            "first_to_assert_smbalert", "output_or_fet_fault", "input_b_or_fet_fault", "input_a_or_fet_fault",
+            "input_b_fuse_fault", "input_a_fuse_fault", "", "",
+        ],
+    },
+    StatusRegister {
+        code: 0x81,
+        name: "status_fans_1_2",
+        size: Size::Byte,
+        bits: &[
+            "airflow_warning", "airflow_fault", "fan_2_speed_overridden", "fan_1_speed_overridden",
+            "fan_2_warning", "fan_1_warning", "fan_2_fault", "fan_1_fault",
+        ],
+    },
+    StatusRegister {
+        code: 0x82,
+        name: "status_fans_3_4",
+        size: Size::Byte,
+        bits: &[
+            "", "", "fan_4_speed_overridden", "fan_3_speed_overridden",
+            "fan_4_warning", "fan_3_warning", "fan_4_fault", "fan_3_fault",
+        ],
+    },
+];
+
+pub const STATUS_BYTE: StatusRegister = StatusRegister {
+    code: 0x78,
+    name: "status_byte",
+    size: Size::Byte,
+    bits: &[
+        "none_of_the_above", "cml", "temperature", "vin_uv_fault",
+        "iout_oc_fault", "vout_ov_fault", "off", "busy",
+    ],
+};
+
+/// Commands read for the status flags
+pub fn commands() -> impl Iterator<Item = u8> {
+    STATUS_REGISTERS.iter().map(|r| r.code).chain([STATUS_BYTE.code])
+}
+
+/// `(register, flag, set)` of every status register of `page` the device
+/// supports and could be read. STATUS_BYTE is only read on devices without
+/// STATUS_WORD.
+pub fn read_flags<T: PmbusTransport + ?Sized>(device: &mut Device, bus: &mut T, page: Option<u8>, log: &mut ReadLog) -> Vec<(&'static str, &'static str, bool)> {
+    let mut flags = Vec::new();
+
+    let mut registers: Vec<&StatusRegister> = STATUS_REGISTERS.iter()
+        .filter(|r| device.supports(r.code))
+        .collect();
+    if !device.supports(STATUS_REGISTERS[0].code) && device.supports(STATUS_BYTE.code) {
+        registers.push(&STATUS_BYTE);
+    }
+
+    for register in registers {
+        let bits = match register.size {
+            Size::Word => device.read_word(bus, page, register.code),
+            _ => device.read_byte(bus, page, register.code).map(u16::from),
+        };
+        let bits = match log.record(register.code, bits) {
+            Some(bits) => bits,
+            None => continue,
+        };
+
+        for (bit, flag) in register.bits.iter().enumerate() {
+            if !flag.is_empty() {
+                flags.push((register.name, *flag, bits & (1 << bit) != 0));
+            }
+        }
+    }
+
+    flags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::transport::MockBus;
+
+    const ADDR: u16 = 0x58;
+
+    fn flags(bus: &mut MockBus) -> Vec<(&'static str, &'static str, bool)> {
+        let config = Config::parse("prefix = \"test\"\n[[device]]\nmodule = \"1\"\naddress = 0x58").unwrap();
+        let mut device = Device::new(&config.devices[0], "test");
+        device.discover(bus, std::iter::empty(), &commands().collect::<Vec<_>>());
+
+        read_flags(&mut device, bus, None, &mut ReadLog::default())
+    }
+
+    fn set<'a>(flags: &'a [(&str, &'a str, bool)], register: &str) -> Vec<&'a str> {
+        flags.iter().filter(|f| f.0 == register && f.2).map(|f| f.1).collect()
+    }
+
+    #[test]
+    fn reads_fan_faults() {
+        let mut bus = MockBus::new();
+        bus.set_word(ADDR, STATUS_REGISTERS[0].code, 1 << 10).set_byte(ADDR, 0x81, 0x80);
+
+        let flags = flags(&mut bus);
+
+        assert_eq!(set(&flags, "status_word"), ["fans"]);
+        assert_eq!(set(&flags, "status_fans_1_2"), ["fan_1_fault"]);
+        assert!(flags.contains(&("status_fans_1_2", "fan_2_fault", false)));
+        assert!(!flags.iter().any(|f| f.0 == "status_byte" || f.0 == "status_fans_3_4"));
+    }
+
+    #[test]
+    fn falls_back_to_status_byte_without_status_word() {
+        let mut bus = MockBus::new();
+        bus.set_byte(ADDR, STATUS_BYTE.code, 0x40);
+
+        let flags = flags(&mut bus);
+
+        assert_eq!(set(&flags, "status_byte"), ["off"]);
+        assert_eq!(flags.len(), STATUS_BYTE.bits.len());
+    }
+}
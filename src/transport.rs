@@ -0,0 +1,421 @@
+use crate::pmbus::{PAGE, PAGE_PLUS_READ, STATUS_CML};
+use i2cdev::core::*;
+use i2cdev::linux::{LinuxI2CDevice, LinuxI2CError};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+
+pub type PMBusResult<T> = Result<T, PMBusError>;
+
+#[derive(Debug)]
+pub enum PMBusError {
+    /// Error reported by the linux i2c-dev driver (NACK, PEC mismatch, ...)
+    I2C(LinuxI2CError),
+    /// No device/register answered at this address
+    NoResponse { addr: u16, com: u8 },
+    /// The command can not be decoded the way it was asked for
+    Unsupported { com: u8, what: &'static str },
+    /// The device answered with something that makes no sense
+    InvalidData { com: u8, what: &'static str },
+}
+
+impl fmt::Display for PMBusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PMBusError::I2C(e) => write!(f, "i2c error: {e}"),
+            PMBusError::NoResponse { addr, com } => {
+                write!(f, "no response from {addr:#04x} for command {com:#04x}")
+            }
+            PMBusError::Unsupported { com, what } => {
+                write!(f, "command {com:#04x}: {what} is not supported")
+            }
+            PMBusError::InvalidData { com, what } => {
+                write!(f, "command {com:#04x}: {what}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PMBusError {}
+
+impl From<LinuxI2CError> for PMBusError {
+    fn from(e: LinuxI2CError) -> Self {
+        PMBusError::I2C(e)
+    }
+}
+
+/// Outcome of the reads of one device during a poll, so a failing command
+/// only costs its own readings.
+#[derive(Debug, Default)]
+pub struct ReadLog {
+    /// Number of reads that succeeded
+    pub answered: usize,
+    /// Command and error of every read that failed
+    pub failed: Vec<(u8, PMBusError)>,
+}
+
+impl ReadLog {
+    pub fn record<T>(&mut self, com: u8, result: PMBusResult<T>) -> Option<T> {
+        match result {
+            Ok(value) => {
+                self.answered += 1;
+                Some(value)
+            }
+            Err(e) => {
+                self.failed.push((com, e));
+                None
+            }
+        }
+    }
+
+    /// The device answered, or nothing was asked
+    pub fn up(&self) -> bool {
+        self.answered > 0 || self.failed.is_empty()
+    }
+}
+
+/// Raw SMBus/PMBus transactions against a single bus.
+///
+/// Every reader and decoder goes through this trait so the exporter can be
+/// pointed at real hardware (`LinuxBus`) or a register map (`MockBus`).
+pub trait PmbusTransport {
+    fn read_byte(&mut self, addr: u16, com: u8) -> PMBusResult<u8>;
+    fn read_word(&mut self, addr: u16, com: u8) -> PMBusResult<u16>;
+    fn read_block(&mut self, addr: u16, com: u8) -> PMBusResult<Vec<u8>>;
+
+    fn write_byte(&mut self, addr: u16, com: u8, val: u8) -> PMBusResult<()>;
+    // nothing the exporter writes takes a word or block yet
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn write_word(&mut self, addr: u16, com: u8, val: u16) -> PMBusResult<()>;
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn write_block(&mut self, addr: u16, com: u8, vals: &[u8]) -> PMBusResult<()>;
+
+    /// Block write-block read process call (COEFFICIENTS, QUERY, ...)
+    fn process_block(&mut self, addr: u16, com: u8, vals: &[u8]) -> PMBusResult<Vec<u8>>;
+}
+
+/// `/dev/i2c-N` backend, PEC is always enabled.
+///
+/// The device handles are opened once per address and kept, a handle is only
+/// dropped when a transaction on it fails so the next one reopens and sets it
+/// up again.
+pub struct LinuxBus {
+    path: String,
+    handles: HashMap<u16, LinuxI2CDevice>,
+}
+
+impl LinuxBus {
+    pub fn new(path: &str) -> Self {
+        LinuxBus {
+            path: path.to_string(),
+            handles: HashMap::new(),
+        }
+    }
+
+    fn open(path: &str, addr: u16) -> PMBusResult<LinuxI2CDevice> {
+        let mut dev = LinuxI2CDevice::new(path, addr)?;
+        dev.set_smbus_pec(true)?;
+
+        Ok(dev)
+    }
+
+    /// Run `f` on the handle of `addr`, opening it if needed
+    fn with<R>(&mut self, addr: u16, f: impl FnOnce(&mut LinuxI2CDevice) -> Result<R, LinuxI2CError>) -> PMBusResult<R> {
+        let dev = match self.handles.entry(addr) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(Self::open(&self.path, addr)?),
+        };
+
+        f(dev).map_err(|e| {
+            self.handles.remove(&addr);
+            e.into()
+        })
+    }
+}
+
+impl PmbusTransport for LinuxBus {
+    fn read_byte(&mut self, addr: u16, com: u8) -> PMBusResult<u8> {
+        self.with(addr, |dev| dev.smbus_read_byte_data(com))
+    }
+
+    fn read_word(&mut self, addr: u16, com: u8) -> PMBusResult<u16> {
+        self.with(addr, |dev| dev.smbus_read_word_data(com))
+    }
+
+    fn read_block(&mut self, addr: u16, com: u8) -> PMBusResult<Vec<u8>> {
+        self.with(addr, |dev| dev.smbus_read_block_data(com))
+    }
+
+    fn write_byte(&mut self, addr: u16, com: u8, val: u8) -> PMBusResult<()> {
+        self.with(addr, |dev| dev.smbus_write_byte_data(com, val))
+    }
+
+    fn write_word(&mut self, addr: u16, com: u8, val: u16) -> PMBusResult<()> {
+        self.with(addr, |dev| dev.smbus_write_word_data(com, val))
+    }
+
+    fn write_block(&mut self, addr: u16, com: u8, vals: &[u8]) -> PMBusResult<()> {
+        self.with(addr, |dev| dev.smbus_write_block_data(com, vals))
+    }
+
+    fn process_block(&mut self, addr: u16, com: u8, vals: &[u8]) -> PMBusResult<Vec<u8>> {
+        self.with(addr, |dev| dev.smbus_process_block(com, vals))
+    }
+}
+
+/// In-memory register map, registers are stored little endian like they
+/// would appear on the wire. Unknown registers behave like a NACK.
+///
+/// Devices become paged once `set_page` is used on them, after that PAGE
+/// selects which registers are read and written, just like on real devices.
+/// Devices with a STATUS_CML register latch invalid command (unknown
+/// commands) and invalid data (missing pages) in it, writing 1s clears them.
+#[derive(Default)]
+pub struct MockBus {
+    regs: HashMap<(u16, u8, u8), Vec<u8>>,
+    calls: HashMap<(u16, u8, Vec<u8>), Vec<u8>>,
+    /// Currently selected page of every paged device
+    pages: HashMap<u16, u8>,
+}
+
+impl MockBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn page(&self, addr: u16) -> u8 {
+        self.pages.get(&addr).copied().unwrap_or(0)
+    }
+
+    /// Select `page` of `addr`, following `set`s go to that page
+    #[cfg(test)]
+    pub fn set_page(&mut self, addr: u16, page: u8) -> &mut Self {
+        self.pages.insert(addr, page);
+        self
+    }
+
+    pub fn set(&mut self, addr: u16, com: u8, data: &[u8]) -> &mut Self {
+        self.regs.insert((addr, self.page(addr), com), data.to_vec());
+        self
+    }
+
+    /// Answer to a process call of `com` with `request` as its data
+    #[cfg(test)]
+    pub fn set_process(&mut self, addr: u16, com: u8, request: &[u8], response: &[u8]) -> &mut Self {
+        self.calls.insert((addr, com, request.to_vec()), response.to_vec());
+        self
+    }
+
+    pub fn set_byte(&mut self, addr: u16, com: u8, val: u8) -> &mut Self {
+        self.set(addr, com, &[val])
+    }
+
+    pub fn set_word(&mut self, addr: u16, com: u8, val: u16) -> &mut Self {
+        self.set(addr, com, &val.to_le_bytes())
+    }
+
+    /// Store `val` encoded as LINEAR11, picking the exponent that keeps the
+    /// most precision.
+    pub fn set_linear11(&mut self, addr: u16, com: u8, val: f32) -> &mut Self {
+        self.set_word(addr, com, Self::linear11(val))
+    }
+
+    /// Store LINEAR11 words back to back as a block (MFR_EFFICIENCY_LL, ...)
+    pub fn set_linear11_block(&mut self, addr: u16, com: u8, vals: &[f32]) -> &mut Self {
+        let data: Vec<u8> = vals.iter().flat_map(|&val| Self::linear11(val).to_le_bytes()).collect();
+        self.set(addr, com, &data)
+    }
+
+    fn linear11(val: f32) -> u16 {
+        let mut exp = -16_i32;
+        while exp < 15 && (val / 2_f32.powi(exp)).abs() > 1023.0 {
+            exp += 1;
+        }
+        let mant = (val / 2_f32.powi(exp)).round() as i16 as u16 & 0x7FF;
+
+        ((exp as u16 & 0x1F) << 11) | mant
+    }
+
+    /// Both modules of an FSP Twins chassis with plausible readings, used by
+    /// `--simulate`.
+    pub fn fsp_twins() -> Self {
+        let mut bus = Self::new();
+
+        for (addr, load, serial) in [(0x58, 1.0, "S0000001"), (0x59, 0.8, "S0000002")] {
+            bus.set(addr, 0x99, b"FSP GROUP")              // MFR_ID
+                .set(addr, 0x9A, b"FSP520-20RAB")           // MFR_MODEL
+                .set(addr, 0x9B, b"A0")                     // MFR_REVISION
+                .set(addr, 0x9E, serial.as_bytes())         // MFR_SERIAL
+                .set_linear11(addr, 0xA0, 90.0)             // MFR_VIN_MIN
+                .set_linear11(addr, 0xA1, 264.0)            // MFR_VIN_MAX
+                .set_linear11(addr, 0xA6, 41.0)             // MFR_IOUT_MAX
+                .set_linear11(addr, 0xA7, 500.0)            // MFR_POUT_MAX
+                .set_linear11_block(addr, 0xAA, &[115.0, 100.0, 82.0, 250.0, 88.0, 500.0, 86.0]) // MFR_EFFICIENCY_LL
+                .set_linear11_block(addr, 0xAB, &[230.0, 100.0, 84.0, 250.0, 90.0, 500.0, 88.0]); // MFR_EFFICIENCY_HL
+            bus.set_byte(addr, 0x20, 0x17)                  // VOUT_MODE, 2^-9
+                .set_linear11(addr, 0x88, 115.0)            // READ_VIN
+                .set_linear11(addr, 0x89, 1.3 * load)       // READ_IIN
+                .set_word(addr, 0x8B, 6144)                 // READ_VOUT, 12V
+                .set_linear11(addr, 0x8C, 10.5 * load)      // READ_IOUT
+                .set_linear11(addr, 0x8D, 31.5 + 4.0 * load) // READ_TEMPERATURE_1
+                .set_linear11(addr, 0x8E, 38.0 + 6.0 * load) // READ_TEMPERATURE_2
+                .set_word(addr, 0x90, (2400.0 * load) as u16) // READ_FAN_SPEED_1
+                .set_linear11(addr, 0x96, 126.0 * load)     // READ_POUT
+                .set_linear11(addr, 0x97, 145.0 * load)     // READ_PIN
+                .set_word(addr, 0x40, 7066)                 // VOUT_OV_FAULT_LIMIT, 13.8V
+                .set_linear11(addr, 0x46, 16.0)             // IOUT_OC_FAULT_LIMIT
+                .set_linear11(addr, 0x4A, 14.0)             // IOUT_OC_WARN_LIMIT
+                .set_linear11(addr, 0x4F, 70.0)             // OT_FAULT_LIMIT
+                .set_linear11(addr, 0x51, 60.0)             // OT_WARN_LIMIT
+                .set_linear11(addr, 0x58, 90.0)             // VIN_UV_WARN_LIMIT
+                .set_byte(addr, 0x3A, 0x90)                 // FAN_CONFIG_1_2, fan 1 in duty cycle, 2 pulses
+                .set_byte(addr, 0x78, 0x00)                 // STATUS_BYTE
+                .set_word(addr, 0x79, 0x0000);              // STATUS_WORD
+            for com in [0x7A, 0x7B, 0x7C, 0x7D, 0x7E, 0x7F, 0x81] {  // STATUS_VOUT..STATUS_FANS_1_2
+                bus.set_byte(addr, com, 0x00);
+            }
+        }
+
+        bus
+    }
+
+    fn get(&self, addr: u16, com: u8, len: usize) -> PMBusResult<&[u8]> {
+        self.get_page(addr, self.page(addr), com, len)
+    }
+
+    fn get_page(&self, addr: u16, page: u8, com: u8, len: usize) -> PMBusResult<&[u8]> {
+        match self.regs.get(&(addr, page, com)) {
+            Some(data) if data.len() >= len => Ok(data),
+            _ => Err(PMBusError::NoResponse { addr, com }),
+        }
+    }
+
+    /// Set `bits` in STATUS_CML of the current page, if the device has one
+    fn latch_cml<T>(&mut self, addr: u16, bits: u8, result: PMBusResult<T>) -> PMBusResult<T> {
+        if result.is_err() {
+            if let Some(cml) = self.regs.get_mut(&(addr, self.page(addr), STATUS_CML)) {
+                cml[0] |= bits;
+            }
+        }
+
+        result
+    }
+}
+
+const INVALID_COMMAND: u8 = 0x80;
+const INVALID_DATA: u8 = 0x40;
+
+impl PmbusTransport for MockBus {
+    fn read_byte(&mut self, addr: u16, com: u8) -> PMBusResult<u8> {
+        let result = match (com, self.pages.get(&addr)) {
+            (PAGE, Some(page)) => Ok(*page),
+            _ => self.get(addr, com, 1).map(|data| data[0]),
+        };
+        self.latch_cml(addr, INVALID_COMMAND, result)
+    }
+
+    fn read_word(&mut self, addr: u16, com: u8) -> PMBusResult<u16> {
+        let result = self.get(addr, com, 2).map(|data| u16::from_le_bytes([data[0], data[1]]));
+        self.latch_cml(addr, INVALID_COMMAND, result)
+    }
+
+    fn read_block(&mut self, addr: u16, com: u8) -> PMBusResult<Vec<u8>> {
+        let result = self.get(addr, com, 0).map(<[u8]>::to_vec);
+        self.latch_cml(addr, INVALID_COMMAND, result)
+    }
+
+    fn write_byte(&mut self, addr: u16, com: u8, val: u8) -> PMBusResult<()> {
+        match com {
+            PAGE => {
+                // unpaged devices and pages without registers NACK
+                if !self.pages.contains_key(&addr) {
+                    return self.latch_cml(addr, INVALID_COMMAND, Err(PMBusError::NoResponse { addr, com }));
+                }
+                if !self.regs.keys().any(|&(a, p, _)| a == addr && p == val) {
+                    return self.latch_cml(addr, INVALID_DATA, Err(PMBusError::NoResponse { addr, com }));
+                }
+                self.pages.insert(addr, val);
+            }
+            STATUS_CML => {
+                let cml = self.get(addr, com, 1)?[0];
+                self.set_byte(addr, com, cml & !val);
+            }
+            _ => {
+                self.set_byte(addr, com, val);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_word(&mut self, addr: u16, com: u8, val: u16) -> PMBusResult<()> {
+        self.set_word(addr, com, val);
+        Ok(())
+    }
+
+    fn write_block(&mut self, addr: u16, com: u8, vals: &[u8]) -> PMBusResult<()> {
+        self.set(addr, com, vals);
+        Ok(())
+    }
+
+    fn process_block(&mut self, addr: u16, com: u8, vals: &[u8]) -> PMBusResult<Vec<u8>> {
+        if let (PAGE_PLUS_READ, true, &[page, page_com]) = (com, self.pages.contains_key(&addr), vals) {
+            let result = self.get_page(addr, page, page_com, 0).map(<[u8]>::to_vec);
+            return self.latch_cml(addr, INVALID_COMMAND, result);
+        }
+
+        let result = match self.calls.get(&(addr, com, vals.to_vec())) {
+            Some(data) => Ok(data.clone()),
+            None => Err(PMBusError::NoResponse { addr, com }),
+        };
+        self.latch_cml(addr, INVALID_COMMAND, result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR: u16 = 0x58;
+
+    #[test]
+    fn mock_writes_read_back() {
+        let mut bus = MockBus::new();
+        bus.write_byte(ADDR, 0x3A, 0x90).unwrap();
+        bus.write_word(ADDR, 0x46, 0xF020).unwrap();
+        bus.write_block(ADDR, 0x99, b"FSP GROUP").unwrap();
+
+        assert_eq!(bus.read_byte(ADDR, 0x3A).unwrap(), 0x90);
+        assert_eq!(bus.read_word(ADDR, 0x46).unwrap(), 0xF020);
+        assert_eq!(bus.read_block(ADDR, 0x99).unwrap(), b"FSP GROUP");
+        assert!(bus.read_word(ADDR + 1, 0x46).is_err());
+    }
+
+    #[test]
+    fn mock_writes_go_to_the_selected_page() {
+        let mut bus = MockBus::new();
+        bus.set_page(ADDR, 1).set_byte(ADDR, STATUS_CML, 0x00)
+            .set_page(ADDR, 0).set_byte(ADDR, STATUS_CML, 0x00);
+
+        bus.write_byte(ADDR, PAGE, 1).unwrap();
+        bus.write_word(ADDR, 0x46, 0x1234).unwrap();
+        assert_eq!(bus.read_word(ADDR, 0x46).unwrap(), 0x1234);
+
+        bus.write_byte(ADDR, PAGE, 0).unwrap();
+        assert!(bus.read_word(ADDR, 0x46).is_err());
+        assert_eq!(bus.read_byte(ADDR, STATUS_CML).unwrap(), INVALID_COMMAND);
+    }
+
+    #[test]
+    fn mock_clears_cml_bits_written_as_1() {
+        let mut bus = MockBus::new();
+        bus.set_byte(ADDR, STATUS_CML, 0x02);
+
+        assert!(bus.write_byte(ADDR, PAGE, 3).is_err());
+        assert!(bus.process_block(ADDR, 0x1A, &[0x88]).is_err());
+        assert_eq!(bus.read_byte(ADDR, STATUS_CML).unwrap(), 0x02 | INVALID_COMMAND);
+
+        bus.write_byte(ADDR, STATUS_CML, INVALID_COMMAND).unwrap();
+        assert_eq!(bus.read_byte(ADDR, STATUS_CML).unwrap(), 0x02);
+    }
+}